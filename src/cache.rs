@@ -0,0 +1,206 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// How many sectors past a cache miss to read and cache along with it, 64KiB worth
+///
+/// Seeking is what's slow on a disc drive, so grabbing a bit extra while we're there is nearly free.
+pub const READ_AHEAD_SECTORS: u32 = 32;
+
+/// Least recently used cache of decrypted sectors
+///
+/// Give one to `PS3Disc::set_cache` and reads that hit the cache skip the drive and the decryption.
+/// Emulators read the same few spots on a disc (SFO files, SELF headers, archive indexes) over and over,
+/// so even a small one goes a long way when playing off a slow drive.
+pub struct SectorCache {
+    /// Most sectors to keep
+    capacity: usize,
+    /// Cached sectors, and when they were last used
+    sectors: HashMap<u32, (u64, Box<[u8; 2048]>)>,
+    /// Cached sectors by when they were last used, oldest first
+    by_use: BTreeMap<u64, u32>,
+    /// Counts up on every use, to keep track of which was the least recent
+    clock: u64,
+    /// Number of sector reads that were served from the cache
+    pub hits: u64,
+    /// Number of sector reads that weren't
+    pub misses: u64
+}
+
+impl SectorCache {
+    /// Make a cache that holds up to `bytes` worth of sectors
+    pub fn with_size(bytes: u64) -> Self {
+        SectorCache {
+            capacity: (bytes / 2048) as usize,
+            sectors: HashMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0
+        }
+    }
+
+    /// Throw away every cached sector, keeping the hit and miss counts
+    pub fn clear(&mut self) {
+        self.sectors.clear();
+        self.by_use.clear();
+    }
+
+    /// Whether a sector's in the cache, without counting it as a use
+    pub fn contains(&self, sector: u32) -> bool {
+        self.sectors.contains_key(&sector)
+    }
+
+    /// Copy a sector out of the cache into `buf`, if it's there
+    pub fn get(&mut self, sector: u32, buf: &mut [u8]) -> bool {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.sectors.get_mut(&sector) {
+            Some(&mut (ref mut last_used, ref data)) => {
+                self.by_use.remove(last_used);
+                self.by_use.insert(clock, sector);
+                *last_used = clock;
+                buf.copy_from_slice(&data[..]);
+                self.hits += 1;
+                true
+            },
+            None => {
+                self.misses += 1;
+                false
+            }
+        }
+    }
+
+    /// Add a decrypted sector to the cache, pushing out the least recently used one if it's full
+    pub fn insert(&mut self, sector: u32, data: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(&mut (ref mut last_used, ref mut cached)) = self.sectors.get_mut(&sector) {
+            self.by_use.remove(last_used);
+            self.by_use.insert(clock, sector);
+            *last_used = clock;
+            cached.copy_from_slice(data);
+            return;
+        }
+        // Reuse the evicted sector's buffer, if there is one
+        let mut cached = if self.sectors.len() >= self.capacity {
+            let oldest = *self.by_use.keys().next().unwrap();
+            let evicted = self.by_use.remove(&oldest).unwrap();
+            self.sectors.remove(&evicted).unwrap().1
+        } else {
+            Box::new([0u8; 2048])
+        };
+        cached.copy_from_slice(data);
+        self.sectors.insert(sector, (clock, cached));
+        self.by_use.insert(clock, sector);
+    }
+}
+
+impl fmt::Display for SectorCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.hits + self.misses;
+        write!(f, "{} hits, {} misses ({}% hit rate), {}/{} sectors cached",
+               self.hits,
+               self.misses,
+               if total == 0 { 0 } else { self.hits * 100 / total },
+               self.sectors.len(),
+               self.capacity)
+    }
+}
+
+impl fmt::Debug for SectorCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SectorCache({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp;
+
+    fn sector(fill: u8) -> [u8; 2048] {
+        [fill; 2048]
+    }
+
+    #[test]
+    fn get_and_insert() {
+        let mut cache = SectorCache::with_size(4 * 2048);
+        let mut buf = [0u8; 2048];
+        assert!(!cache.get(5, &mut buf));
+        cache.insert(5, &sector(5));
+        assert!(cache.contains(5));
+        assert!(cache.get(5, &mut buf));
+        assert_eq!(&buf[..], &sector(5)[..]);
+
+        // Inserting a cached sector again replaces its data
+        cache.insert(5, &sector(6));
+        assert!(cache.get(5, &mut buf));
+        assert_eq!(&buf[..], &sector(6)[..]);
+        assert_eq!((cache.hits, cache.misses), (2, 1));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = SectorCache::with_size(3 * 2048 + 100);
+        let mut buf = [0u8; 2048];
+        for i in 0..3 {
+            cache.insert(i, &sector(i as u8));
+        }
+        // 0 is now the most recently used, leaving 1 the oldest
+        assert!(cache.get(0, &mut buf));
+        cache.insert(3, &sector(3));
+        assert!(!cache.contains(1));
+        assert!(cache.contains(0) && cache.contains(2) && cache.contains(3));
+        // Then 2, and re-inserting 0 counts as a use too
+        cache.insert(0, &sector(0));
+        cache.insert(4, &sector(4));
+        assert!(!cache.contains(2));
+        cache.insert(5, &sector(5));
+        assert!(!cache.contains(3));
+        assert!(cache.contains(0) && cache.contains(4) && cache.contains(5));
+        // The evicted sector's buffer gets reused, so make sure it got the new data
+        assert!(cache.get(5, &mut buf));
+        assert_eq!(&buf[..], &sector(5)[..]);
+    }
+
+    #[test]
+    fn capacity() {
+        let mut cache = SectorCache::with_size(16 * 2048);
+        for i in 0..100 {
+            cache.insert(i, &sector(i as u8));
+            assert_eq!(cache.sectors.len(), cmp::min(i as usize + 1, 16));
+            assert_eq!(cache.by_use.len(), cache.sectors.len());
+        }
+        assert!((84..100).all(|i| cache.contains(i)));
+        assert_eq!(cache.to_string(), "0 hits, 0 misses (0% hit rate), 16/16 sectors cached");
+
+        let mut off = SectorCache::with_size(2047);
+        off.insert(0, &sector(0));
+        assert!(!off.contains(0));
+        assert!(!off.get(0, &mut [0u8; 2048]));
+        assert_eq!(off.misses, 1);
+    }
+
+    #[test]
+    fn hit_rate_and_clear() {
+        let mut cache = SectorCache::with_size(8 * 2048);
+        let mut buf = [0u8; 2048];
+        cache.insert(1, &sector(1));
+        cache.insert(2, &sector(2));
+        for &i in &[1, 2, 1, 3] {
+            cache.get(i, &mut buf);
+        }
+        assert_eq!(cache.to_string(), "3 hits, 1 misses (75% hit rate), 2/8 sectors cached");
+
+        cache.clear();
+        assert!(!cache.contains(1) && !cache.contains(2));
+        assert!(!cache.get(1, &mut buf));
+        assert_eq!(cache.to_string(), "3 hits, 2 misses (60% hit rate), 0/8 sectors cached");
+        // Still usable after clearing
+        cache.insert(1, &sector(1));
+        assert!(cache.get(1, &mut buf));
+    }
+}
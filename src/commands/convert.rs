@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Write};
+
+use super::super::errors::*;
+use super::super::{disc, ird, config};
+
+pub fn convert_disc(matches: &::clap::ArgMatches) -> Result<()> {
+    println!("input: {}", PathBuf::from(matches.value_of("FILE").unwrap()).display());
+    let f = File::open(matches.value_of("FILE").unwrap()).chain_err(|| "Failed to open file")?;
+    let reader = BufReader::new(f);
+
+    let mut disc = disc::PS3Disc::new(reader)?;
+    let strip = matches.is_present("strip");
+
+    // Calculate output filename
+    let output_path = if let Some(outfile) = matches.value_of("OUTFILE") {
+        PathBuf::from(outfile)
+    } else {
+        // game.iso becomes game.3k3y.iso, or game.plain.iso when stripping
+        let input = matches.value_of("FILE").unwrap();
+        let base = if input.ends_with(".iso") {
+            input[..(input.len()-4)].to_string()
+        } else {
+            disc.gameid.replace('-', "")
+        };
+        PathBuf::from(format!("{}.{}.iso", base, if strip {"plain"} else {"3k3y"}))
+    };
+    if output_path == PathBuf::from(matches.value_of("FILE").unwrap()) {
+        bail!("Output file is the same as the input file");
+    }
+
+    // Work out what sector 1 should end up as
+    let mut sector1 = disc.read_sector_raw(1).chain_err(|| "Failed to read sector 1")?;
+    if strip {
+        if disc.tagline_3k3y.is_none() {
+            bail!("Image doesn't have any 3k3y data to strip");
+        }
+        disc::strip_3k3y(&mut sector1);
+    } else {
+        if let Some(ref tagline) = disc.tagline_3k3y {
+            bail!("Image already has 3k3y data (\"{}\"), use --strip to remove it", tagline);
+        }
+        let (ird_path, ird_file) = if let Some(ird_path) = matches.value_of("irdfile") {
+            (PathBuf::from(ird_path), ird::read_ird(ird_path)?)
+        } else if let Some(found) = config::find_ird_file(&mut disc)? {
+            found
+        } else {
+            bail!("No IRD file specified or found for {}, it's needed for the d1 and d2 keys", disc.gameid);
+        };
+        println!("ird: {}", ird_path.display());
+        if ird_file.game_id != disc.gameid.replace('-', "") {
+            bail!("IRD file is for {} but the disc is {}, is this the right IRD?", ird_file.game_id, disc.gameid);
+        }
+        if !config::ird_layout_matches(&disc, &ird_file) {
+            bail!("IRD file's region layout doesn't match the disc, is this the right IRD?");
+        }
+        if ird_file.data1 == [0; 16] {
+            bail!("IRD file appears to be corrupted, its d1 key is zeroed!");
+        }
+        disc::inject_3k3y(&mut sector1, matches.is_present("decrypted"), &ird_file.data1, &ird_file.data2);
+    }
+    println!("output: {}", output_path.display());
+
+    let fout = File::create(output_path).chain_err(|| "Failed to create file")?;
+    let mut writer = BufWriter::new(fout);
+    for i in 0..disc.total_sectors {
+        if i == 1 {
+            writer.write_all(sector1.as_ref()).chain_err(|| "Failed to write sector")?;
+        } else {
+            let buf = disc.read_sector_raw(i).chain_err(|| format!("Failed to read sector {}", i))?;
+            writer.write_all(buf.as_ref()).chain_err(|| "Failed to write sector")?;
+        }
+        if i % 512 == 0 || i == disc.total_sectors - 1 {
+            print!("\rsector: {}/{} ({}%)",
+                   i + 1,
+                   disc.total_sectors,
+                   (((i + 1) as f64)/(disc.total_sectors as f64)*100f64).floor()
+            );
+            ::std::io::stdout().flush().ok();
+        }
+    }
+    println!();
+    writer.flush().chain_err(|| "Failed to write file")?;
+    Ok(())
+}
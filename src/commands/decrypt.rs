@@ -1,14 +1,14 @@
 
-use std::fs::File;
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
 use std::ffi::OsStr;
-use std::io::{BufReader, BufWriter, Write, Seek, SeekFrom};
-use std::sync::{mpsc, Arc, Mutex};
-use std::thread;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom};
 use bytesize::ByteSize;
 
 use super::super::errors::*;
-use super::super::disc;
+use super::super::{disc, parallel};
+use super::super::journal::{Journal, JOURNAL_INTERVAL};
+use super::super::output::SyncWrite;
 
 pub fn decrypt_disc(matches: &::clap::ArgMatches) -> Result<()> {
     println!("input: {}", PathBuf::from(matches.value_of("FILE").unwrap()).display());
@@ -35,7 +35,14 @@ pub fn decrypt_disc(matches: &::clap::ArgMatches) -> Result<()> {
     };
 
     println!("output: {}", output_path.display());
-    let fout = File::create(output_path).chain_err(|| "Failed to create file")?;
+    // Don't truncate when resuming, that'd throw away everything we're resuming from
+    let resume = matches.is_present("resume");
+    let mut fout = OpenOptions::new().read(true).write(true).create(true).truncate(!resume)
+        .open(&output_path).chain_err(|| "Failed to create file")?;
+    if !resume {
+        // The output's just been truncated, so a journal left over from an earlier run would be wrong
+        Journal::new(&disc, &output_path).remove()?;
+    }
 
 
     if !super::find_key_if_possible(&mut disc, matches).chain_err(||"Failed to try and find a key")? && !disc.can_decrypt() {
@@ -59,13 +66,21 @@ pub fn decrypt_disc(matches: &::clap::ArgMatches) -> Result<()> {
              size=ByteSize::b(disc.total_sectors as usize * 2048).to_string(true),
              regions=disc.regions.len());
 
+    // Pick up where we left off, if asked to and there's a journal to do it with
+    let journal = if resume {
+        resume_journal(&mut disc, &output_path, &mut fout)?
+    } else {
+        Journal::new(&disc, &output_path)
+    };
+    let start_sector = journal.completed;
+
     // Check to make sure we have enough free disk space
     #[cfg(unix)]
     {
         use nix::sys::statvfs::fstatvfs;
         let statvfs = fstatvfs(&fout).chain_err(|| "failed to check disk space")?;
         let free_space = (statvfs.blocks() * statvfs.block_size()) as usize;
-        let needed_space = disc.total_sectors as usize * 2048;
+        let needed_space = (disc.total_sectors - start_sector) as usize * 2048;
         if free_space < needed_space {
             bail!("need {need} bytes free ({needf}), only have {have} bytes ({havef})",
                 need=needed_space, needf=ByteSize::b(needed_space).to_string(true),
@@ -77,12 +92,50 @@ pub fn decrypt_disc(matches: &::clap::ArgMatches) -> Result<()> {
 
     // Start the actual decryption/ripping process
 
-    let mut writer = BufWriter::new(fout);
     let threads = matches.value_of("threads").unwrap_or("1").parse::<usize>().unwrap();
+    if threads == 0 {
+        println!("must specify a -j/--threads value of 1 or more");
+        return Ok(());
+    }
+    decrypt_with_journal(disc, &mut BufWriter::new(fout), threads, journal)
+}
+
+/// Load and check the journal of an earlier decrypt into `output`, or start a new one if there isn't one
+///
+/// It's an error if the output doesn't match the disc as far as the journal says it got.
+fn resume_journal<F: Read+Seek, O: Read+Seek>(disc: &mut disc::PS3Disc<F>, output_path: &Path, output: &mut O) -> Result<Journal> {
+    match Journal::load(disc, output_path)? {
+        Some(journal) => {
+            if !journal.validate(disc, output)? {
+                bail!("Output doesn't match the disc up to sector {}, can't resume. Run again without --resume to start over", journal.completed - 1);
+            }
+            println!("resuming from sector {}", journal.completed);
+            Ok(journal)
+        },
+        None => {
+            println!("no journal found, starting from the beginning");
+            Ok(Journal::new(disc, output_path))
+        }
+    }
+}
+
+/// Decrypt the disc into `writer` from where the journal says it got to, keeping the journal up to date as it goes
+///
+/// The journal's removed once the whole disc has been written.
+fn decrypt_with_journal<F, W>(mut disc: disc::PS3Disc<F>, writer: &mut W, threads: usize, mut journal: Journal) -> Result<()>
+    where F: Read+Seek+Send+'static, W: SyncWrite+Seek {
+    let start_sector = journal.completed;
     if threads == 1 {
         // Singlethreaded Decrypt
-        for i in 0..disc.total_sectors {
-            writer.write_all(disc.read_sector(i).chain_err(|| "failed to read something")?.as_ref()).chain_err(|| "failed to write something")?;
+        writer.seek(SeekFrom::Start(start_sector as u64 * 2048)).chain_err(|| "failed to seek")?;
+        let mut buf = [0u8; 2048];
+        for i in start_sector..disc.total_sectors {
+            disc.read_sector_into(i, &mut buf).chain_err(|| "failed to read something")?;
+            writer.write_all(&buf).chain_err(|| "failed to write something")?;
+            if (i + 1) % JOURNAL_INTERVAL == 0 {
+                writer.sync().chain_err(|| "failed to write something")?;
+                journal.record(i + 1)?;
+            }
             print!("\rsector: {}/{} ({}%)",
                    i,
                    disc.total_sectors,
@@ -90,66 +143,102 @@ pub fn decrypt_disc(matches: &::clap::ArgMatches) -> Result<()> {
             );
         }
         println!();
-    } else if threads > 1 {
+        writer.flush().chain_err(|| "failed to write something")?;
+    } else {
         // Multithreaded Decrypt
-        let total_sectors = disc.total_sectors;
-        let decryptor = disc.get_decryptor().chain_err(|| "Failed to get standalone disc decryptor")?;
-        let writer = Arc::new(Mutex::new(writer));
-        let disc = Arc::new(Mutex::new((0u32, disc)));
-        let (tx, rx) = mpsc::channel();
-
-        for _ in 0..threads {
-            let (writer, disc, tx) = (Arc::clone(&writer), Arc::clone(&disc), tx.clone());
-            let decryptor = decryptor.clone();
-            thread::spawn(move || {
-                let mut encrypted: Vec<u8>; //TODO switch one or both of these to [u8; 2048]?
-                let mut decrypted: Vec<u8>;
-                let mut cur_sec: u32;
-                loop {
-                    {
-                        let (ref mut current_sector, ref mut disc) = *disc.lock().unwrap();
-                        cur_sec = *current_sector;
-                        if *current_sector >= disc.total_sectors {
-                            // Why are you doing this like this?
-                            // Well, we need to inform the main thread that we're done
-                            // But if this thread isn't the first to inform,
-                            // then the mpsc channel will have already been dropped,
-                            // causing a panic:
-                            // > thread '<unnamed>' panicked at 'called `Result::unwrap()`
-                            // > on an `Err` value: "SendError(..)"'
-                            // This throws out the result in a way rustc is happy with.
-                            // (no unused_must_use warning)
-                            match tx.send(true) {
-                                _ => break
-                            };
-                        } else {
-                            tx.send(false).unwrap();
-                        }
-                        encrypted = disc.read_sector_raw(*current_sector).unwrap();
-                        *current_sector += 1;
-                    }
-                    decrypted = decryptor.decrypt_sector(&encrypted, cur_sec).unwrap();
-                    {
-                        let mut writer = writer.lock().unwrap();
-                        writer.seek(SeekFrom::Start(cur_sec as u64 * 2048)).unwrap();
-                        writer.write_all(decrypted.as_ref()).unwrap();
-                    }
-                }
-            });
-        }
-
-        let mut progress = 0;
-        while rx.recv().unwrap() != true {
-            progress += 1;
+        parallel::decrypt_disc_synced(disc, writer, threads, start_sector, |writer| writer.sync(), |progress| {
+            if let Some(flushed) = progress.flushed {
+                journal.record(flushed)?;
+            }
             print!("\rsector: {}/{} ({}%)",
-                   progress,
-                   total_sectors,
-                   ((progress as f64) / (total_sectors as f64) * 100f64).floor()
+                   progress.done,
+                   progress.total,
+                   ((progress.done as f64) / (progress.total as f64) * 100f64).floor()
             );
-        }
+            Ok(())
+        })?;
         println!();
-    } else {
-        println!("must specify a -j/--threads value of 1 or more");
     }
-    Ok(())
-}
\ No newline at end of file
+    journal.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+    use std::io::Cursor;
+    use disc::tests::{test_disc, test_image};
+
+    fn output_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("ps3bdtool-test-{}-{}.dec.iso", process::id(), name))
+    }
+
+    /// The whole test disc, decrypted
+    fn decrypted() -> Vec<u8> {
+        let mut disc = test_disc(test_image());
+        let mut data = vec![0u8; disc.total_sectors as usize * 2048];
+        disc.read_sectors_into(0, &mut data).unwrap();
+        data
+    }
+
+    /// Resume a decrypt whose journal says the first 20 sectors were written
+    fn resume(threads: usize, name: &str) {
+        let expected = decrypted();
+        let output_path = output_path(name);
+        let mut disc = test_disc(test_image());
+        Journal::new(&disc, &output_path).record(20).unwrap();
+
+        // Only the last sector the journal covers gets checked, so fill in the rest
+        // with junk to show they're left alone
+        let mut output = expected[..20 * 2048].to_vec();
+        for b in &mut output[..19 * 2048] {
+            *b = 0xEE;
+        }
+        let mut output = Cursor::new(output);
+        let journal = resume_journal(&mut disc, &output_path, &mut output).unwrap();
+        assert_eq!(journal.completed, 20);
+        decrypt_with_journal(disc, &mut output, threads, journal).unwrap();
+
+        let output = output.into_inner();
+        assert!(!Journal::path_for(&output_path).exists());
+        assert_eq!(output.len(), expected.len());
+        assert!(output[..19 * 2048].iter().all(|&b| b == 0xEE));
+        assert!(output[19 * 2048..] == expected[19 * 2048..]);
+    }
+
+    #[test]
+    fn resume_one_thread() {
+        resume(1, "resume-one-thread");
+    }
+
+    #[test]
+    fn resume_parallel() {
+        resume(4, "resume-parallel");
+    }
+
+    #[test]
+    fn no_journal_starts_over() {
+        let output_path = output_path("resume-no-journal");
+        let mut disc = test_disc(test_image());
+        let mut output = Cursor::new(vec![0xEE; 10 * 2048]);
+        let journal = resume_journal(&mut disc, &output_path, &mut output).unwrap();
+        assert_eq!(journal.completed, 0);
+        decrypt_with_journal(disc, &mut output, 2, journal).unwrap();
+        assert!(output.into_inner() == decrypted());
+    }
+
+    #[test]
+    fn resume_rejects_the_wrong_output() {
+        let output_path = output_path("resume-wrong-output");
+        let mut disc = test_disc(test_image());
+        let mut journal = Journal::new(&disc, &output_path);
+        journal.record(20).unwrap();
+        let short = resume_journal(&mut disc, &output_path, &mut Cursor::new(decrypted()[..19 * 2048].to_vec())).is_err();
+        let mut output = decrypted();
+        output[19 * 2048] ^= 1;
+        let mismatched = resume_journal(&mut disc, &output_path, &mut Cursor::new(output)).is_err();
+        journal.remove().unwrap();
+        assert!(short);
+        assert!(mismatched);
+    }
+}
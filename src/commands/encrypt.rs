@@ -0,0 +1,78 @@
+use std::fs::File;
+use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Write};
+use bytesize::ByteSize;
+
+use super::super::errors::*;
+use super::super::disc;
+
+pub fn encrypt_disc(matches: &::clap::ArgMatches) -> Result<()> {
+    println!("input: {}", PathBuf::from(matches.value_of("FILE").unwrap()).display());
+    let f = File::open(matches.value_of("FILE").unwrap()).chain_err(|| "Failed to open file")?;
+    let reader = BufReader::new(f);
+
+    let mut disc = disc::PS3Disc::new(reader)?;
+
+    if disc.tagline_3k3y.as_ref().map_or(false, |t| t.starts_with("Encrypted")) {
+        bail!("3k3y tagline says this image is already encrypted");
+    }
+
+    // Calculate output filename
+    let output_path = if let Some(outfile) = matches.value_of("OUTFILE") {
+        PathBuf::from(outfile)
+    } else {
+        // game.dec.iso or game.iso becomes game.enc.iso
+        let input = matches.value_of("FILE").unwrap();
+        if input.ends_with(".dec.iso") {
+            PathBuf::from(format!("{}.enc.iso", &input[..(input.len()-8)]))
+        } else if input.ends_with(".iso") {
+            PathBuf::from(format!("{}.enc.iso", &input[..(input.len()-4)]))
+        } else {
+            PathBuf::from(format!("{}.enc.iso", disc.gameid.replace('-', "")))
+        }
+    };
+    if output_path == PathBuf::from(matches.value_of("FILE").unwrap()) {
+        bail!("Output file is the same as the input file");
+    }
+    println!("output: {}", output_path.display());
+
+    if !super::find_key_if_possible(&mut disc, matches).chain_err(||"Failed to try and find a key")? && !disc.can_decrypt() {
+        println!("No 3k3y header found, and no d1, disc key, or ird file specified!");
+        println!("Disc can't be encrypted without any of those.");
+        println!("Consider passing a value to --d1 or --ird");
+        return Ok(());
+    }
+
+    if let Some(d1) = disc.d1 {
+        print!("using d1: ");
+        hex_println!(d1.as_ref());
+    }
+    print!("{} disc key: ", if disc.d1.is_some() {"calculated"} else {"using"});
+    hex_println!(disc.disc_key.unwrap().as_ref());
+
+    println!("sectors: {sectors} ({size}), regions: {regions}",
+             sectors=disc.total_sectors,
+             size=ByteSize::b(disc.total_sectors as usize * 2048).to_string(true),
+             regions=disc.regions.len());
+
+    let encryptor = disc.get_decryptor().chain_err(|| "Failed to get standalone disc decryptor")?;
+    let fout = File::create(output_path).chain_err(|| "Failed to create file")?;
+    let mut writer = BufWriter::new(fout);
+    for i in 0..disc.total_sectors {
+        // Read raw, since the image is already decrypted and read_sector would try to decrypt it again
+        let decrypted = disc.read_sector_raw(i).chain_err(|| format!("Failed to read sector {}", i))?;
+        let encrypted = encryptor.encrypt_sector(&decrypted, i).chain_err(|| format!("Failed to encrypt sector {}", i))?;
+        writer.write_all(encrypted.as_ref()).chain_err(|| "Failed to write sector")?;
+        if i % 512 == 0 || i == disc.total_sectors - 1 {
+            print!("\rsector: {}/{} ({}%)",
+                   i + 1,
+                   disc.total_sectors,
+                   (((i + 1) as f64)/(disc.total_sectors as f64)*100f64).floor()
+            );
+            ::std::io::stdout().flush().ok();
+        }
+    }
+    println!();
+    writer.flush().chain_err(|| "Failed to write file")?;
+    Ok(())
+}
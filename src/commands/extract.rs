@@ -0,0 +1,198 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::ffi::OsStr;
+use bytesize::ByteSize;
+
+use super::super::errors::*;
+use super::super::disc;
+use super::super::iso9660::{self, DirectoryRecord};
+
+pub fn extract_disc(matches: &::clap::ArgMatches) -> Result<()> {
+    println!("input: {}", PathBuf::from(matches.value_of("FILE").unwrap()).display());
+    let f = File::open(matches.value_of("FILE").unwrap()).chain_err(|| "Failed to open file")?;
+    let reader = BufReader::new(f);
+
+    let mut disc = disc::PS3Disc::new(reader)?;
+    if matches.is_present("decrypted") {
+        disc.set_decrypted(true);
+    }
+
+    let output_dir = PathBuf::from(matches.value_of("OUTDIR").unwrap());
+    println!("output: {}", output_dir.display());
+
+    if !disc.decrypted && !super::find_key_if_possible(&mut disc, matches).chain_err(||"Failed to try and find a key")? && !disc.can_decrypt() {
+        println!("No 3k3y header found, and no d1, disc key, or ird file specified!");
+        println!("Disc can't be decrypted without any of those.");
+        println!("Consider passing a value to --d1 or --ird");
+        return Ok(());
+    }
+
+    let filters: Vec<String> = matches.values_of("only")
+        .map(|v| v.map(|f| f.trim_matches('/').to_lowercase()).collect())
+        .unwrap_or_default();
+
+    // Find everything we need to extract first, so we can give some idea of progress
+    let (mut dirs, files) = find_entries(&mut disc, &filters)?;
+    let total_size: u64 = files.iter().map(|f| f.1.size).sum();
+    println!("files: {}, {}", files.len(), ByteSize::b(total_size as usize).to_string(true));
+
+    for &(ref path, _) in &dirs {
+        fs::create_dir_all(output_dir.join(path)).chain_err(|| format!("Failed to create directory {}", path))?;
+    }
+
+    for (i, &(ref path, ref record)) in files.iter().enumerate() {
+        println!("[{}/{}] {}", i+1, files.len(), path);
+        let out_path = output_dir.join(path);
+        extract_file(&mut disc, record, &out_path).chain_err(|| format!("Failed to extract {}", path))?;
+        set_mtime(&out_path, record.recorded_timestamp())?;
+    }
+
+    // Do directories last, since writing files into them changes their times
+    // and deepest first, for the same reason
+    dirs.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    for &(ref path, ref record) in &dirs {
+        set_mtime(&output_dir.join(path), record.recorded_timestamp())?;
+    }
+    Ok(())
+}
+
+/// Every directory and file on the disc to extract, by path, with the root directory first
+///
+/// This checks every name on the disc before anything gets extracted, filtered out or not.
+fn find_entries<F: Read+Seek>(disc: &mut disc::PS3Disc<F>, filters: &[String]) -> Result<(Vec<(String, DirectoryRecord)>, Vec<(String, DirectoryRecord)>)> {
+    let root = iso9660::root_directory(disc).chain_err(|| "Failed to read the disc's filesystem")?;
+    let mut dirs = vec![(String::new(), root)];
+    let mut files = vec![];
+    for (path, entry) in iso9660::walk_all(disc).chain_err(|| "Failed to read the disc's filesystem")? {
+        // Names come straight off the disc, make sure they can't put anything outside output_dir
+        check_name(&path, &entry.name)?;
+        if !wanted(&path, filters, entry.is_dir()) {
+            continue;
+        }
+        if entry.is_dir() {
+            dirs.push((path, entry));
+        } else {
+            files.push((path, entry));
+        }
+    }
+    Ok((dirs, files))
+}
+
+/// Make sure a name from the disc's filesystem is just a name, not a path
+///
+/// Anything absolute, `.`, `..`, or with a path separator in it could be used to write outside
+/// the output directory, so it's an error naming the entry at `path`.
+fn check_name(path: &str, name: &str) -> Result<()> {
+    let mut components = Path::new(name).components();
+    let plain = match (components.next(), components.next()) {
+        (Some(Component::Normal(part)), None) => part == OsStr::new(name),
+        _ => false
+    };
+    if !plain || name.contains(|c| c == '/' || c == '\\' || c == '\0') {
+        bail!("Refusing to extract {:?}, {:?} isn't a valid file name", path, name);
+    }
+    Ok(())
+}
+
+/// Whether a path should be extracted given the `--only` filters
+///
+/// Directories that lead to a filtered path are wanted too, so we can get there.
+fn wanted(path: &str, filters: &[String], is_dir: bool) -> bool {
+    if filters.is_empty() {
+        return true;
+    }
+    let path = path.to_lowercase();
+    filters.iter().any(|filter| {
+        path == *filter
+            || path.starts_with(&format!("{}/", filter))
+            || (is_dir && filter.starts_with(&format!("{}/", path)))
+    })
+}
+
+/// Copy a single file out of the disc
+fn extract_file<F: Read+Seek>(disc: &mut disc::PS3Disc<F>, record: &DirectoryRecord, out_path: &Path) -> Result<()> {
+    let mut file = iso9660::open(disc, record)?;
+    let out = File::create(out_path).chain_err(|| "Failed to create file")?;
+    let mut writer = BufWriter::new(out);
+    io::copy(&mut file, &mut writer).chain_err(|| "Failed to copy file")?;
+    writer.flush().chain_err(|| "Failed to write file")?;
+    Ok(())
+}
+
+/// Set a file or directory's access and modification times
+#[cfg(unix)]
+fn set_mtime(path: &Path, timestamp: i64) -> Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use libc;
+    let c_path = CString::new(path.as_os_str().as_bytes()).chain_err(|| "Path contains a null byte")?;
+    let time = libc::timeval {
+        tv_sec: timestamp as libc::time_t,
+        tv_usec: 0
+    };
+    let times = [time, time];
+    if unsafe { libc::utimes(c_path.as_ptr(), times.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error()).chain_err(|| format!("Failed to set the time on {}", path.display()));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+#[allow(unused_variables)]
+fn set_mtime(path: &Path, timestamp: i64) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disc::tests::test_disc;
+    use iso9660::tests::{record, test_iso, test_iso_with, SFB_SECTOR};
+
+    fn paths(entries: &[(String, DirectoryRecord)]) -> Vec<&str> {
+        entries.iter().map(|e| e.0.as_str()).collect()
+    }
+
+    #[test]
+    fn check_name() {
+        for name in &["PS3_DISC.SFB", "USRDIR", "EBOOT.BIN", "..A", "A..B"] {
+            assert!(super::check_name(name, name).is_ok(), "{:?}", name);
+        }
+        for name in &["", ".", "..", "/", "/etc", "A/B", "../A", "A\\B", "A\0B"] {
+            assert!(super::check_name(name, name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn finds_everything() {
+        let mut disc = test_disc(test_iso());
+        let (dirs, files) = find_entries(&mut disc, &[]).unwrap();
+        assert_eq!(paths(&dirs), vec!["", "PS3_GAME"]);
+        let mut files = paths(&files);
+        files.sort();
+        assert_eq!(files, vec!["BIG.DAT", "PS3_DISC.SFB", "PS3_GAME/PARAM.SFO"]);
+
+        let (dirs, files) = find_entries(&mut disc, &["ps3_game/param.sfo".to_string()]).unwrap();
+        assert_eq!(paths(&dirs), vec!["", "PS3_GAME"]);
+        assert_eq!(paths(&files), vec!["PS3_GAME/PARAM.SFO"]);
+    }
+
+    #[test]
+    fn rejects_unsafe_names_on_the_disc() {
+        // ".." and "." as actual names rather than the special 0 and 1 bytes, which come out empty
+        // once the trailing dots are trimmed off
+        let names: &[&[u8]] = &[b"..", b".", b"../../ETC;1", b"/ETC;1", b"A/B", b"A\\B"];
+        for name in names {
+            let bad = record(name, SFB_SECTOR, 512, 0);
+            for &(root, game) in &[(&[bad.clone()][..], &[][..]), (&[][..], &[bad.clone()][..])] {
+                let mut disc = test_disc(test_iso_with(root, game));
+                let e = find_entries(&mut disc, &[]).unwrap_err();
+                assert!(e.to_string().starts_with("Refusing to extract"), "{:?}: {}", String::from_utf8_lossy(name), e);
+                // Even when it's been filtered out
+                let mut disc = test_disc(test_iso_with(root, game));
+                assert!(find_entries(&mut disc, &["ps3_disc.sfb".to_string()]).is_err());
+            }
+        }
+    }
+}
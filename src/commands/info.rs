@@ -5,16 +5,29 @@ use std::path::PathBuf;
 use bytesize::ByteSize;
 
 use super::super::errors::*;
-use super::super::{disc, ird};
+use super::super::{disc, ird, sfo, keyfile};
 
 pub fn disc_info(matches: &::clap::ArgMatches) -> Result<()> {
     let f = File::open(matches.value_of("FILE").unwrap()).chain_err(|| "Failed to open file")?;
     let reader = BufReader::new(f);
 
-    let disc = disc::PS3Disc::new(reader)?;
+    let mut disc = disc::PS3Disc::new(reader)?;
     if matches.is_present("id") {
         println!("{}", disc.gameid);
-    } else if matches.is_present("keys") {
+    } else if matches.is_present("keys") || matches.is_present("export") {
+        if !disc.can_decrypt() || matches.is_present("irdfile") || matches.is_present("keyfile") {
+            super::find_key_if_possible(&mut disc, matches)?;
+        }
+        if let Some(export_path) = matches.value_of("export") {
+            if !disc.can_decrypt() {
+                bail!("No keys found to export");
+            }
+            keyfile::write_key_file(export_path, &keyfile::KeyFile::from_disc(&disc))?;
+            println!("keys written to {}", export_path);
+            if !matches.is_present("keys") {
+                return Ok(());
+            }
+        }
         if let Some(disc_key) = disc.disc_key {
             if let Some(d1) = disc.d1 {
                 print!("      d1: ");
@@ -26,14 +39,20 @@ pub fn disc_info(matches: &::clap::ArgMatches) -> Result<()> {
             println!("No keys present");
         }
     } else {
-        //TODO get the game name
-        println!("{filename}: {gameid}, {bytes}, {regions} regions",
+        // PARAM.SFO is normally unencrypted, so only bother with keys if we were given some
+        if matches.is_present("irdfile") || matches.is_present("keyfile") {
+            super::find_key_if_possible(&mut disc, matches).chain_err(|| "Failed to load keys")?;
+        }
+        let param_sfo = sfo::read_param_sfo(&mut disc);
+
+        println!("{filename}: {gameid}, {title}{bytes}, {regions} regions",
                  filename=matches.value_of("FILE").unwrap(),
                  gameid=disc.gameid,
+                 title=param_sfo.as_ref().ok().and_then(|s| s.get_str("TITLE")).map_or(String::new(), |t| format!("\"{}\", ", t)),
                  bytes=ByteSize::b((disc.total_sectors as usize)*2048).to_string(true),
                  regions=disc.regions.len()
         );
-        for region in disc.regions {
+        for region in &disc.regions {
             println!("Region {id}: sectors {start:X}-{end:X} ({start}-{end}), {encrypted}",
                      id=region.id,
                      start=region.start,
@@ -43,9 +62,32 @@ pub fn disc_info(matches: &::clap::ArgMatches) -> Result<()> {
         }
         println!("https://rpcs3.net/compatibility?g={}",
                  disc.gameid.replace('-', ""));
-        if let Some(tagline) = disc.tagline_3k3y {
+        if let Some(ref tagline) = disc.tagline_3k3y {
             println!("3k3y tagline present: \"{}\"", tagline);
         }
+        match disc.metadata() {
+            Ok(metadata) => {
+                println!("PS3_DISC.SFB:");
+                println!("  VERSION: {}.{}", metadata.version >> 16, metadata.version & 0xFFFF);
+                println!("  HYBRID_FLAG: {} ({})", metadata.hybrid_flag, metadata.contents().join(", "));
+                println!("  TITLE_ID: {}", metadata.title_id);
+            },
+            Err(e) => println!("Couldn't read PS3_DISC.SFB: {}", e)
+        }
+        match param_sfo {
+            Ok(param_sfo) => {
+                println!("PARAM.SFO:");
+                for key in &["TITLE", "TITLE_ID", "VERSION", "APP_VER", "CATEGORY", "PS3_SYSTEM_VER", "ATTRIBUTE", "RESOLUTION"] {
+                    match param_sfo.get(key) {
+                        Some(&sfo::SFOValue::String(ref value)) => println!("  {}: {}", key, value),
+                        Some(&sfo::SFOValue::Integer(value)) => println!("  {}: {:#010X}", key, value),
+                        Some(&sfo::SFOValue::Binary(ref value)) => println!("  {}: {} bytes", key, value.len()),
+                        None => {}
+                    }
+                }
+            },
+            Err(e) => println!("Couldn't read PARAM.SFO: {}", e)
+        }
     }
     Ok(())
 }
@@ -66,6 +108,16 @@ pub fn ird_info(matches: &::clap::ArgMatches) -> Result<()> {
         hex_println!(parsed.data1.as_ref());
         print!("data2: ");
         hex_println!(parsed.data2.as_ref());
+        if let Some(unique_identifier) = parsed.unique_identifier {
+            println!("unique identifier: {:08X}", unique_identifier);
+        }
+        println!("crc32: {:08X} (verified)", parsed.crc32);
+
+        let header_sectors = parsed.header_sectors()?;
+        let footer_sectors = parsed.footer_sectors()?;
+        println!("header: {} sectors ({}), footer: {} sectors ({})",
+                 header_sectors, ByteSize::b(header_sectors as usize * 2048).to_string(true),
+                 footer_sectors, ByteSize::b(footer_sectors as usize * 2048).to_string(true));
 
         println!();
         println!("Region MD5 hashes:");
@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::io::{BufReader, Cursor};
+use std::path::PathBuf;
+
+use super::super::errors::*;
+use super::super::{disc, ird, decrypt, keydb};
+use super::super::keydb::{KeyDatabase, KeyEntry};
+
+pub fn keys(matches: &::clap::ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("list", Some(_)) => list_keys(),
+        ("add", Some(matches)) => add_key(matches),
+        ("remove", Some(matches)) => remove_key(matches),
+        ("import", Some(matches)) => import_irds(matches),
+        _ => Ok(())
+    }
+}
+
+fn list_keys() -> Result<()> {
+    let db = KeyDatabase::load()?;
+    if db.entries.is_empty() {
+        println!("Key database is empty");
+    }
+    for entry in &db.entries {
+        print!("{} {} ", entry.game_id, entry.fingerprint);
+        hex_println!(entry.disc_key.as_ref());
+    }
+    Ok(())
+}
+
+fn add_key(matches: &::clap::ArgMatches) -> Result<()> {
+    let f = File::open(matches.value_of("FILE").unwrap()).chain_err(|| "Failed to open file")?;
+    let reader = BufReader::new(f);
+
+    let mut disc = disc::PS3Disc::new(reader)?;
+    if !super::find_key_if_possible(&mut disc, matches).chain_err(||"Failed to try and find a key")? && !disc.can_decrypt() {
+        bail!("No 3k3y header found, and no d1, disc key, key file, or ird file specified!");
+    }
+
+    let entry = KeyEntry {
+        game_id: keydb::normalize_game_id(disc.gameid.as_ref()),
+        fingerprint: keydb::fingerprint(&mut disc)?,
+        d1: disc.d1,
+        disc_key: disc.disc_key.unwrap()
+    };
+    save_entry(entry)
+}
+
+fn remove_key(matches: &::clap::ArgMatches) -> Result<()> {
+    let mut db = KeyDatabase::load()?;
+    let removed = db.remove(matches.value_of("GAMEID").unwrap(), matches.value_of("FINGERPRINT"));
+    if removed == 0 {
+        bail!("No matching keys in the key database");
+    }
+    let path = db.save()?;
+    println!("removed {} {} from {}", removed, if removed == 1 {"entry"} else {"entries"}, path.display());
+    Ok(())
+}
+
+fn import_irds(matches: &::clap::ArgMatches) -> Result<()> {
+    let mut db = KeyDatabase::load()?;
+    let mut failed = 0;
+    for ird_path in matches.values_of("IRD").unwrap() {
+        match ird_entry(ird_path) {
+            Ok(entry) => {
+                println!("{}: {} {}", PathBuf::from(ird_path).display(), entry.game_id, entry.fingerprint);
+                db.add(entry);
+            },
+            Err(e) => {
+                failed += 1;
+                println!("{}: {}", PathBuf::from(ird_path).display(), e);
+            }
+        }
+    }
+    let path = db.save()?;
+    println!("saved to {}", path.display());
+    if failed > 0 {
+        bail!("{} IRD files couldn't be imported", failed);
+    }
+    Ok(())
+}
+
+/// Build a key database entry out of an IRD file, using the disc header inside it for the fingerprint
+fn ird_entry(ird_path: &str) -> Result<KeyEntry> {
+    let ird_file = ird::read_ird(ird_path)?;
+    if ird_file.data1 == [0; 16] {
+        bail!("IRD file appears to be corrupted, its d1 key is zeroed!");
+    }
+    let mut disc = disc::PS3Disc::new(Cursor::new(ird_file.header()?)).chain_err(|| "Failed to read the disc header in the IRD file")?;
+    let mut disc_key = [0u8; 16];
+    disc_key.copy_from_slice(decrypt::disc_key(&ird_file.data1).chain_err(|| "Failed to generate disc key")?.as_ref());
+    Ok(KeyEntry {
+        game_id: keydb::normalize_game_id(disc.gameid.as_ref()),
+        fingerprint: keydb::fingerprint(&mut disc)?,
+        d1: Some(ird_file.data1),
+        disc_key
+    })
+}
+
+fn save_entry(entry: KeyEntry) -> Result<()> {
+    let mut db = KeyDatabase::load()?;
+    let description = format!("{} {}", entry.game_id, entry.fingerprint);
+    let replaced = db.add(entry);
+    let path = db.save()?;
+    println!("{} {} in {}", if replaced {"updated"} else {"added"}, description, path.display());
+    Ok(())
+}
@@ -1,15 +1,23 @@
+pub mod convert;
 pub mod decrypt;
+pub mod encrypt;
+pub mod extract;
 pub mod info;
+pub mod keys;
+pub mod rip;
+pub mod verify;
 
 use std::io::prelude::*;
 use hex::FromHex;
-use super::{disc, ird, config};
+use super::{disc, ird, config, keyfile, keydb};
 use super::errors::*;
 
 pub fn find_key_if_possible<F: Read+Seek>(disc: &mut disc::PS3Disc<F>, matches: &::clap::ArgMatches) -> Result<bool> {
     // Check if a IRD file has been passed on the command line
-    if matches.is_present("irdfile") && (matches.is_present("d1") || matches.is_present("key")) {
-        println!("warning: --ird takes precedence over --key/--d1");
+    if matches.is_present("irdfile") && (matches.is_present("keyfile") || matches.is_present("d1") || matches.is_present("key")) {
+        println!("warning: --ird takes precedence over --keyfile/--key/--d1");
+    } else if matches.is_present("keyfile") && (matches.is_present("d1") || matches.is_present("key")) {
+        println!("warning: --keyfile takes precedence over --key/--d1");
     }
 
     if let Some(ird_path) = matches.value_of("irdfile") {
@@ -18,6 +26,12 @@ pub fn find_key_if_possible<F: Read+Seek>(disc: &mut disc::PS3Disc<F>, matches:
         return Ok(true);
     }
 
+    // Then a key file passed on the command line
+    if let Some(key_path) = matches.value_of("keyfile") {
+        keyfile::read_key_file(key_path)?.apply(disc)?;
+        return Ok(true);
+    }
+
 
     // Check if the user passed us --d1 and/or --key, and set them accordingly
     if matches.is_present("d1") && matches.is_present("key") {
@@ -35,9 +49,30 @@ pub fn find_key_if_possible<F: Read+Seek>(disc: &mut disc::PS3Disc<F>, matches:
     }
 
 
-    // If nothing was specified by the user, check their folders for it.
-    if let Some(ird_path) = config::find_ird_file(disc.gameid.replace('-', "").as_ref()).chain_err(||"argh")? {
-        let parsed = ird::read_ird(ird_path)?;
+    // If nothing was specified by the user, look for a key file next to the image
+    if let Some(key_path) = matches.value_of("FILE").and_then(keyfile::find_key_file) {
+        println!("using key file: {}", key_path.display());
+        keyfile::read_key_file(key_path)?.apply(disc)?;
+        return Ok(true);
+    }
+
+
+    // or in the key database
+    let (keys, bad_lines) = keydb::KeyDatabase::load_lenient()?;
+    for e in bad_lines {
+        println!("warning: skipping a bad line in the key database, {}", e);
+    }
+    let fingerprint = keydb::fingerprint(disc)?;
+    if let Some(entry) = keys.find(disc.gameid.as_ref(), fingerprint.as_ref()) {
+        println!("using keys from the key database");
+        entry.apply(disc)?;
+        return Ok(true);
+    }
+
+
+    // and then check their folders for it.
+    if let Some((ird_path, parsed)) = config::find_ird_file(disc)? {
+        println!("using ird: {}", ird_path.display());
         disc.import_from_ird(&parsed)?;
         return Ok(true);
     }
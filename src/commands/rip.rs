@@ -0,0 +1,99 @@
+use std::fs::{File, OpenOptions};
+use std::path::PathBuf;
+use std::io::{stdout, Write};
+use bytesize::ByteSize;
+
+use super::super::errors::*;
+use super::super::disc;
+use super::super::rescue::{self, RescueMap, RipOptions, SectorStatus};
+
+pub fn rip_disc(matches: &::clap::ArgMatches) -> Result<()> {
+    println!("input: {}", PathBuf::from(matches.value_of("FILE").unwrap()).display());
+    // No BufReader, a failed read would take a whole buffer's worth of good sectors down with it
+    let mut input = File::open(matches.value_of("FILE").unwrap()).chain_err(|| "Failed to open file")?;
+
+    let (gameid, total_sectors) = {
+        let disc = disc::PS3Disc::new(&mut input).chain_err(|| "Failed to read the disc header, can't rip without it")?;
+        (disc.gameid.clone(), disc.total_sectors)
+    };
+
+    let output_path = if let Some(outfile) = matches.value_of("OUTFILE") {
+        PathBuf::from(outfile)
+    } else {
+        PathBuf::from(format!("{}.iso", gameid.replace('-', "")))
+    };
+    let map_path = if let Some(map) = matches.value_of("map") {
+        PathBuf::from(map)
+    } else {
+        let mut path = output_path.as_os_str().to_owned();
+        path.push(".map");
+        PathBuf::from(path)
+    };
+    println!("output: {}", output_path.display());
+    println!("map file: {}", map_path.display());
+    println!("sectors: {sectors} ({size})",
+             sectors=total_sectors,
+             size=ByteSize::b(total_sectors as usize * 2048).to_string(true));
+
+    let retries = matches.value_of("retries").unwrap_or("3").parse::<u32>().chain_err(|| "--retries must be a number")?;
+
+    // An existing map means this is a later pass, so only go after what's missing
+    let (mut map, existing) = match RescueMap::load(&map_path, total_sectors)? {
+        Some(map) => {
+            println!("resuming: {} sectors done, {} bad, {} not tried",
+                     map.count(SectorStatus::Finished), map.count(SectorStatus::BadSector), map.count(SectorStatus::NonTried));
+            (map, true)
+        },
+        None => (RescueMap::new(&map_path, total_sectors), false)
+    };
+    // The map says what's already in the output, so it's no use without it
+    if existing && !output_path.exists() {
+        bail!("Map file {} exists but the output {} doesn't, delete the map to start over",
+              map_path.display(), output_path.display());
+    }
+    if map.is_finished() {
+        println!("Every sector has already been ripped");
+        return Ok(());
+    }
+
+    let mut output = OpenOptions::new().write(true).create(true).truncate(!existing)
+        .open(&output_path).chain_err(|| "Failed to create file")?;
+
+    let todo = total_sectors - map.count(SectorStatus::Finished);
+    let mut done = 0;
+    let stats = rescue::rip(&mut input, &mut output, &mut map, RipOptions { retries }, |sector, status| {
+        done += 1;
+        if status == SectorStatus::BadSector {
+            println!("\rbad sector: {}", sector);
+        }
+        if done % 512 == 0 || done == todo {
+            print!("\rsector: {}/{} ({}%)",
+                   done,
+                   todo,
+                   ((done as f64)/(todo as f64)*100f64).floor()
+            );
+            stdout().flush().ok();
+        }
+    })?;
+    println!();
+    println!("read {} sectors", stats.read);
+
+    let bad_ranges = map.bad_ranges();
+    if !bad_ranges.is_empty() {
+        println!("bad sectors:");
+        for (start, count) in bad_ranges {
+            if count == 1 {
+                println!("  {}", start);
+            } else {
+                println!("  {}-{} ({} sectors)", start, start + count - 1, count);
+            }
+        }
+        for &(sector, ref error) in stats.bad.iter().take(10) {
+            println!("sector {}: {}", sector, error);
+        }
+        bail!("{} sectors couldn't be read and were zero-filled, run the same command again to retry them",
+              map.count(SectorStatus::BadSector));
+    }
+    println!("All {} sectors ripped", total_sectors);
+    Ok(())
+}
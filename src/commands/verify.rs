@@ -0,0 +1,115 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, Write, stdout};
+use std::path::PathBuf;
+
+use super::super::errors::*;
+use super::super::{disc, ird, iso9660, config, verify};
+
+pub fn verify_disc(matches: &::clap::ArgMatches) -> Result<()> {
+    println!("input: {}", PathBuf::from(matches.value_of("FILE").unwrap()).display());
+    let f = File::open(matches.value_of("FILE").unwrap()).chain_err(|| "Failed to open file")?;
+    let reader = BufReader::new(f);
+
+    let mut disc = disc::PS3Disc::new(reader)?;
+    if matches.is_present("decrypted") {
+        disc.set_decrypted(true);
+    }
+
+    // Find the IRD file with the hashes in it
+    let (ird_path, ird_file) = if let Some(ird_path) = matches.value_of("irdfile") {
+        (PathBuf::from(ird_path), ird::read_ird(ird_path)?)
+    } else if let Some(found) = config::find_ird_file(&mut disc)? {
+        found
+    } else {
+        bail!("No IRD file specified or found for {}, can't verify without one", disc.gameid);
+    };
+    println!("ird: {}", ird_path.display());
+
+    if !disc.decrypted && !super::find_key_if_possible(&mut disc, matches).chain_err(||"Failed to try and find a key")? && !disc.can_decrypt() {
+        bail!("Disc is encrypted and no key could be found, pass --decrypted if it's already been decrypted");
+    }
+
+    if matches.is_present("files") {
+        return verify_files(&mut disc, &ird_file);
+    }
+
+    if ird_file.region_hashes.len() != disc.regions.len() {
+        bail!("IRD file has {} region hashes but the disc has {} regions, is this the right IRD?",
+              ird_file.region_hashes.len(), disc.regions.len());
+    }
+
+    let mut failed = 0;
+    for (region, expected) in disc.regions.clone().iter().zip(ird_file.region_hashes.iter()) {
+        let check = verify::check_region(&mut disc, region, *expected, |sector| {
+            if sector % 512 == 0 || sector == region.end {
+                print!("\rRegion {}: sector {}/{}", region.id, sector - region.start + 1, region.end - region.start + 1);
+                stdout().flush().ok();
+            }
+        })?;
+        print!("\rRegion {}: ", region.id);
+        if check.passed() {
+            print!("OK ");
+            hex_println!(check.actual.as_ref());
+        } else {
+            failed += 1;
+            print!("MISMATCH, expected ");
+            for &byte in check.expected.as_ref() {
+                print!("{:02X}", byte);
+            }
+            print!(", got ");
+            hex_println!(check.actual.as_ref());
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} of {} regions failed verification", failed, disc.regions.len());
+    }
+    println!("All {} regions verified", disc.regions.len());
+    Ok(())
+}
+
+/// Check each file in the IRD file's hash list, instead of whole regions
+fn verify_files<F: Read+Seek>(disc: &mut disc::PS3Disc<F>, ird_file: &ird::IRDFile) -> Result<()> {
+    let total = ird_file.file_hashes.len();
+    let mut checked = 0;
+    let files = match iso9660::walk(disc) {
+        Ok(files) => files,
+        Err(e) => {
+            println!("warning: couldn't read the disc's filesystem, so files can't be verified: {}", e);
+            vec![]
+        }
+    };
+    let checks = verify::check_files(disc, ird_file, &files, |check| {
+        checked += 1;
+        print!("\rfile: {}/{}", checked, total);
+        stdout().flush().ok();
+        let name = match check.path {
+            Some(ref path) => format!("{} (sector {})", path, check.sector),
+            None => format!("file at sector {}", check.sector)
+        };
+        match check.status {
+            verify::FileStatus::Ok | verify::FileStatus::Unverifiable => {},
+            verify::FileStatus::Mismatch(ref actual) => {
+                print!("\rMISMATCH {}, expected ", name);
+                for &byte in check.expected.as_ref() {
+                    print!("{:02X}", byte);
+                }
+                print!(", got ");
+                hex_println!(actual.as_ref());
+            },
+            verify::FileStatus::ReadError(ref e) => println!("\rREAD ERROR {}: {}", name, e)
+        }
+    })?;
+    println!();
+
+    let failed = checks.iter().filter(|c| c.failed()).count();
+    if failed > 0 {
+        bail!("{} of {} files failed verification", failed, total);
+    }
+    let unverifiable = checks.iter().filter(|c| c.status == verify::FileStatus::Unverifiable).count();
+    if unverifiable > 0 {
+        bail!("{} of {} files aren't in the disc's filesystem, so they couldn't be verified", unverifiable, total);
+    }
+    println!("All {} files verified", total);
+    Ok(())
+}
@@ -1,32 +1,303 @@
 use super::errors::*;
 use std::path::PathBuf;
+use std::io::{Cursor, Read, Seek, Write, stdout};
+use disc::PS3Disc;
+use ird::{IRDFile, read_ird};
+use verify;
 #[cfg(unix)]
 use std::fs::{read_dir, DirEntry};
 
-/// Automatically find an IRD file to use
+/// Find every IRD file for a game ID
 ///
 /// Path is `$XDG_DATA_HOME/ps3bdtool/ird_files/`
 ///
 /// so, probably, `~/.local/share/ps3bdtool/ird_files/`
 ///
+/// Any file with the game ID in its name counts, so there may be more than one
+/// if there's multiple revisions of the game. Use `find_ird_file` to pick the right one.
 #[cfg(unix)]
-pub fn find_ird_file(gameid: &str) -> Result<Option<PathBuf>> {
+pub fn find_ird_files(gameid: &str) -> Result<Vec<PathBuf>> {
     use xdg;
     let xdg_dirs: xdg::BaseDirectories = xdg::BaseDirectories::with_prefix("ps3bdtool").chain_err(|| "Failed to get base directories")?;
     let ird_dir = xdg_dirs.create_data_directory("ird_files").chain_err(|| "Failed to get ird files directory")?;
+    let mut found = vec![];
     for file in read_dir(ird_dir).chain_err(||"failed to read directory")? {
         let dir_entry: DirEntry = file.chain_err(||"failed to read file")?;
         let filename = dir_entry.file_name();
         let filename = filename.to_string_lossy();
         if filename.as_ref().contains(gameid) {
-            return Ok(Some(dir_entry.path()))
+            found.push(dir_entry.path());
         }
     }
-    Ok(None)
+    // Directory order is up to the filesystem, so make it consistent
+    found.sort();
+    Ok(found)
 }
 
 #[cfg(not(unix))]
 #[allow(unused_variables)]
-pub fn find_ird_file(gameid: &str) -> Result<Option<PathBuf>> {
+pub fn find_ird_files(gameid: &str) -> Result<Vec<PathBuf>> {
+    Ok(vec![])
+}
+
+/// Whether the disc header in an IRD file has the same size and region layout as a disc
+pub fn ird_layout_matches<F>(disc: &PS3Disc<F>, ird_file: &IRDFile) -> bool {
+    let header = match ird_file.header() {
+        Ok(header) => header,
+        Err(_) => return false
+    };
+    match PS3Disc::new(Cursor::new(header)) {
+        Ok(ird_disc) => {
+            ird_disc.total_sectors == disc.total_sectors
+                && ird_disc.regions.len() == disc.regions.len()
+                && ird_file.region_hashes.len() == disc.regions.len()
+                && ird_disc.regions.iter().zip(disc.regions.iter())
+                    .all(|(a, b)| a.start == b.start && a.end == b.end && a.encrypted == b.encrypted)
+        },
+        Err(_) => false
+    }
+}
+
+/// Automatically find the IRD file for a disc
+///
+/// If there's more than one IRD file for the game ID, the one whose disc header has the same
+/// region layout as the disc is used. If that's still not enough, the hashes of
+/// the unencrypted regions, which don't need a key to check, are compared too.
+/// That means reading those regions, so progress is printed while it's happening.
+///
+/// If no IRD file matches the disc, or more than one does, it's an error rather than a guess.
+pub fn find_ird_file<F: Read+Seek>(disc: &mut PS3Disc<F>) -> Result<Option<(PathBuf, IRDFile)>> {
+    let paths = find_ird_files(disc.gameid.replace('-', "").as_ref())?;
+    pick_ird_file(disc, paths)
+}
+
+/// Pick which of `paths` is the IRD file for a disc, see `find_ird_file`
+fn pick_ird_file<F: Read+Seek>(disc: &mut PS3Disc<F>, paths: Vec<PathBuf>) -> Result<Option<(PathBuf, IRDFile)>> {
+    if paths.is_empty() {
+        return Ok(None);
+    }
+
+    let mut candidates = vec![];
+    let mut first_error = None;
+    for path in paths {
+        match read_ird(&path) {
+            Ok(ird_file) => candidates.push((path, ird_file)),
+            Err(e) => if first_error.is_none() {
+                first_error = Some(Error::with_chain(e, format!("Failed to read IRD file {}", path.display())));
+            }
+        }
+    }
+    if candidates.is_empty() {
+        return Err(first_error.unwrap());
+    }
+    let found = candidates.len();
+
+    // Compare the region layout in each IRD file's copy of the disc header
+    candidates.retain(|&(_, ref ird_file)| ird_layout_matches(disc, ird_file));
+
+    // Then the unencrypted region hashes, only hashing regions the IRD files disagree on
+    for region in disc.regions.clone() {
+        if candidates.len() < 2 {
+            break;
+        }
+        let id = region.id as usize;
+        if region.encrypted || candidates.iter().all(|c| c.1.region_hashes[id] == candidates[0].1.region_hashes[id]) {
+            continue;
+        }
+        let count = candidates.len();
+        let actual = verify::region_md5(disc, &region, |sector| {
+            if sector % 512 == 0 || sector == region.end {
+                print!("\rhashing region {} to pick between {} IRD files: sector {}/{}",
+                       region.id, count, sector - region.start + 1, region.end - region.start + 1);
+                stdout().flush().ok();
+            }
+        })?;
+        println!();
+        candidates.retain(|c| c.1.region_hashes[id] == actual);
+    }
+
+    // Copies of the same IRD file are fine, they'll all have the same key
+    if candidates.len() > 1 && candidates.iter().all(|c| c.1.data1 == candidates[0].1.data1) {
+        candidates.truncate(1);
+    }
+
+    match candidates.len() {
+        0 => bail!("Found {} IRD files for {}, but none of them match this disc", found, disc.gameid),
+        1 => Ok(candidates.pop()),
+        _ => bail!("Found {} IRD files for {} that all match this disc, pass the right one with --ird: {}",
+                   candidates.len(), disc.gameid,
+                   candidates.iter().map(|c| c.0.display().to_string()).collect::<Vec<String>>().join(", "))
+    }
+}
+
+/// Where the key database lives
+///
+/// Path is `$XDG_DATA_HOME/ps3bdtool/keys.txt`
+#[cfg(unix)]
+pub fn key_database_path() -> Result<Option<PathBuf>> {
+    use xdg;
+    let xdg_dirs: xdg::BaseDirectories = xdg::BaseDirectories::with_prefix("ps3bdtool").chain_err(|| "Failed to get base directories")?;
+    let path = xdg_dirs.place_data_file("keys.txt").chain_err(|| "Failed to get key database path")?;
+    Ok(Some(path))
+}
+
+#[cfg(not(unix))]
+pub fn key_database_path() -> Result<Option<PathBuf>> {
     Ok(None)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+    use std::fs::File;
+    use std::path::Path;
+    use disc::tests::{test_disc, test_image, test_image_with_regions};
+    use ird::{ird_crc32, read_ird_bytes};
+    use ird::tests::{gzip, ird_bytes};
+
+    /// Overwrite the 16 bytes of `ird` that are all `fill` with `value`
+    fn patch(ird: &mut [u8], fill: u8, value: &[u8; 16]) {
+        let pos = ird.windows(16).position(|w| w == [fill; 16]).unwrap();
+        ird[pos..(pos + 16)].copy_from_slice(value);
+    }
+
+    /// A decompressed IRD file with the header of `image`, and the given region hashes and data1
+    fn ird_for(image: &[u8], region_hashes: &[[u8; 16]; 3], data1: u8) -> Vec<u8> {
+        let mut ird = ird_bytes(9, &gzip(&image[..4096]), &gzip(b"FOOTER"));
+        for (i, hash) in region_hashes.iter().enumerate() {
+            patch(&mut ird, 0xA0 + i as u8, hash);
+        }
+        patch(&mut ird, 0xD1, &[data1; 16]);
+        let crc = ird_crc32(&ird);
+        let len = ird.len();
+        ird[(len - 4)..].copy_from_slice(&[crc as u8, (crc >> 8) as u8, (crc >> 16) as u8, (crc >> 24) as u8]);
+        ird
+    }
+
+    /// The real region hashes of `test_image`, other than the encrypted region which gets `0xEE`s
+    fn test_image_hashes() -> [[u8; 16]; 3] {
+        let mut disc = test_disc(test_image());
+        let mut hashes = [[0xEE; 16]; 3];
+        for region in disc.regions.clone() {
+            if !region.encrypted {
+                hashes[region.id as usize] = verify::region_md5(&mut disc, &region, |_| {}).unwrap();
+            }
+        }
+        hashes
+    }
+
+    /// Write out each of `irds`, gzipped like the real thing
+    fn write_irds(name: &str, irds: &[Vec<u8>]) -> Vec<PathBuf> {
+        let dir = env::temp_dir().join(format!("ps3bdtool-test-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        irds.iter().enumerate().map(|(i, ird)| {
+            let path = dir.join(format!("BLUS99999-{}.ird", i));
+            File::create(&path).unwrap().write_all(&gzip(ird)).unwrap();
+            path
+        }).collect()
+    }
+
+    fn remove_irds(paths: &[PathBuf]) {
+        fs::remove_dir_all(paths[0].parent().unwrap()).ok();
+    }
+
+    fn pick(name: &str, irds: &[Vec<u8>]) -> Result<Option<PathBuf>> {
+        let paths = write_irds(name, irds);
+        let picked = pick_ird_file(&mut test_disc(test_image()), paths.clone());
+        remove_irds(&paths);
+        picked.map(|found| found.map(|(path, _)| path))
+    }
+
+    fn file_name(path: &Path) -> &str {
+        path.file_name().unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn layout_matches() {
+        let disc = test_disc(test_image());
+        let hashes = test_image_hashes();
+        let matching = read_ird_bytes(&ird_for(&test_image(), &hashes, 1)).unwrap();
+        assert!(ird_layout_matches(&disc, &matching));
+
+        // Same number of regions and sectors, but the regions are somewhere else
+        let moved = read_ird_bytes(&ird_for(&test_image_with_regions(&[0, 1, 20, 39]), &hashes, 1)).unwrap();
+        assert!(!ird_layout_matches(&disc, &moved));
+        let shorter = read_ird_bytes(&ird_for(&test_image_with_regions(&[0, 1, 30, 38]), &hashes, 1)).unwrap();
+        assert!(!ird_layout_matches(&disc, &shorter));
+
+        // Headers that aren't a disc header at all
+        let garbage = read_ird_bytes(&ird_for(&[0u8; 4096], &hashes, 1)).unwrap();
+        assert!(!ird_layout_matches(&disc, &garbage));
+        let not_gzipped = read_ird_bytes(&ird_bytes(9, &test_image()[..4096], b"")).unwrap();
+        assert!(!ird_layout_matches(&disc, &not_gzipped));
+    }
+
+    #[test]
+    fn picks_by_layout() {
+        let hashes = test_image_hashes();
+        let moved = ird_for(&test_image_with_regions(&[0, 1, 20, 39]), &hashes, 1);
+        let matching = ird_for(&test_image(), &hashes, 2);
+        let picked = pick("pick-layout", &[moved.clone(), matching.clone()]).unwrap().unwrap();
+        assert_eq!(file_name(&picked), "BLUS99999-1.ird");
+        let picked = pick("pick-layout-reversed", &[matching, moved]).unwrap().unwrap();
+        assert_eq!(file_name(&picked), "BLUS99999-0.ird");
+    }
+
+    #[test]
+    fn picks_by_region_hashes() {
+        let hashes = test_image_hashes();
+        // Same layout, but the last unencrypted region hashes differently, as if it were another revision
+        let mut other_hashes = hashes;
+        other_hashes[2] = [0x77; 16];
+        let picked = pick("pick-md5", &[ird_for(&test_image(), &other_hashes, 1), ird_for(&test_image(), &hashes, 2)]).unwrap().unwrap();
+        assert_eq!(file_name(&picked), "BLUS99999-1.ird");
+
+        // Encrypted regions can't be checked without a key, so differences there don't count
+        let mut encrypted_differs = hashes;
+        encrypted_differs[1] = [0x77; 16];
+        let e = pick("pick-md5-encrypted", &[ird_for(&test_image(), &encrypted_differs, 1), ird_for(&test_image(), &hashes, 2)]).unwrap_err();
+        assert!(e.to_string().contains("that all match this disc"), "{}", e);
+    }
+
+    #[test]
+    fn ambiguous_is_an_error() {
+        let hashes = test_image_hashes();
+        // Both match the disc, but have different keys
+        let e = pick("pick-ambiguous", &[ird_for(&test_image(), &hashes, 1), ird_for(&test_image(), &hashes, 2)]).unwrap_err();
+        let message = e.to_string();
+        assert!(message.starts_with("Found 2 IRD files for BLUS-99999 that all match this disc"), "{}", message);
+        assert!(message.contains("BLUS99999-0.ird") && message.contains("BLUS99999-1.ird"), "{}", message);
+
+        // Copies of the same IRD are fine
+        let picked = pick("pick-copies", &[ird_for(&test_image(), &hashes, 1), ird_for(&test_image(), &hashes, 1)]).unwrap().unwrap();
+        assert_eq!(file_name(&picked), "BLUS99999-0.ird");
+    }
+
+    #[test]
+    fn nothing_matches() {
+        let mut hashes = test_image_hashes();
+        assert!(pick_ird_file(&mut test_disc(test_image()), vec![]).unwrap().is_none());
+
+        let moved = ird_for(&test_image_with_regions(&[0, 1, 20, 39]), &hashes, 1);
+        let shorter = ird_for(&test_image_with_regions(&[0, 1, 30, 38]), &hashes, 2);
+        let e = pick("pick-none", &[moved, shorter]).unwrap_err();
+        assert!(e.to_string().starts_with("Found 2 IRD files for BLUS-99999, but none of them match"), "{}", e);
+
+        // Hashes are only compared to pick between IRD files, so both need to be wrong
+        hashes[0] = [0x77; 16];
+        let wrong_hash = ird_for(&test_image(), &hashes, 1);
+        hashes[0] = [0x78; 16];
+        let also_wrong_hash = ird_for(&test_image(), &hashes, 2);
+        let e = pick("pick-none-md5", &[wrong_hash, also_wrong_hash]).unwrap_err();
+        assert!(e.to_string().starts_with("Found 2 IRD files for BLUS-99999, but none of them match"), "{}", e);
+
+        // Unreadable IRD files are skipped, unless there's nothing else
+        let mut corrupt = ird_for(&test_image(), &test_image_hashes(), 1);
+        corrupt[20] ^= 1;
+        let picked = pick("pick-corrupt", &[corrupt.clone(), ird_for(&test_image(), &test_image_hashes(), 1)]).unwrap().unwrap();
+        assert_eq!(file_name(&picked), "BLUS99999-1.ird");
+        let e = pick("pick-only-corrupt", &[corrupt]).unwrap_err();
+        assert!(e.to_string().starts_with("Failed to read IRD file"), "{}", e);
+    }
+}
@@ -1,7 +1,10 @@
 
 use super::errors::*;
-use crypto::{buffer, aes, blockmodes};
+use crypto::{buffer, aes, aessafe, blockmodes};
 use crypto::buffer::{ReadBuffer, WriteBuffer, BufferResult};
+use crypto::symmetriccipher::BlockDecryptor;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crypto::{aesni, util};
 
 /// High-level, simple function to do an AES128 CBC encrypt using rust-crypto
 pub fn aes_encrypt(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
@@ -33,32 +36,51 @@ pub fn aes_encrypt(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
     Ok(final_result)
 }
 
-/// High-level, simple function to do an AES128 CBC decrypt using rust-crypto
+/// High-level, simple function to do an AES128 CBC decrypt
+///
+/// Gives back a copy, use `aes_decrypt_in_place` to skip the allocation.
 pub fn aes_decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
-    let mut decryptor = aes::cbc_decryptor(
-        aes::KeySize::KeySize128,
-        key,
-        iv,
-        blockmodes::NoPadding);
-
-    let mut final_result = Vec::<u8>::new();
-    let mut read_buffer = buffer::RefReadBuffer::new(encrypted_data);
-    let mut buffer = [0; 2048];
-    let mut write_buffer = buffer::RefWriteBuffer::new(&mut buffer);
+    let mut decrypted = encrypted_data.to_vec();
+    aes_decrypt_in_place(&mut decrypted, key, iv)?;
+    Ok(decrypted)
+}
 
-    loop {
-        let result = match decryptor.decrypt(&mut read_buffer, &mut write_buffer, true) {
-            Ok(k) => k,
-            Err(e) => return Err(Error::from_kind(ErrorKind::SymmetricCipherError(e)))
-        };
-        final_result.extend(write_buffer.take_read_buffer().take_remaining().iter().cloned());
-        match result {
-            BufferResult::BufferUnderflow => break,
-            BufferResult::BufferOverflow => { }
+/// AES128 CBC decrypt a buffer in place, without allocating anything
+///
+/// Uses AES-NI if the CPU has it, same as rust-crypto's own `aes::cbc_decryptor`.
+/// `data` has to be a whole number of 16 byte blocks.
+pub fn aes_decrypt_in_place(data: &mut [u8], key: &[u8], iv: &[u8]) -> Result<()> {
+    if key.len() != 16 || iv.len() != 16 {
+        bail!("AES128 needs a 16 byte key and iv");
+    }
+    if data.len() % 16 != 0 {
+        bail!("AES CBC can only decrypt whole 16 byte blocks, got {} bytes", data.len());
+    }
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if util::supports_aesni() {
+            cbc_decrypt_in_place(&aesni::AesNiDecryptor::new(aes::KeySize::KeySize128, key), data, iv);
+            return Ok(());
         }
     }
+    cbc_decrypt_in_place(&aessafe::AesSafe128Decryptor::new(key), data, iv);
+    Ok(())
+}
 
-    Ok(final_result)
+/// CBC mode by hand, since rust-crypto's only works between two separate buffers
+fn cbc_decrypt_in_place<D: BlockDecryptor>(decryptor: &D, data: &mut [u8], iv: &[u8]) {
+    let mut previous = [0u8; 16];
+    previous.copy_from_slice(iv);
+    let mut encrypted = [0u8; 16];
+    let mut decrypted = [0u8; 16];
+    for block in data.chunks_mut(16) {
+        encrypted.copy_from_slice(block);
+        decryptor.decrypt_block(&encrypted, &mut decrypted);
+        for i in 0..16 {
+            block[i] = decrypted[i] ^ previous[i];
+        }
+        previous = encrypted;
+    }
 }
 
 /// Calculate a disc encryption key, given the disc's d1
@@ -70,4 +92,60 @@ pub fn disc_key(d1: &[u8]) -> Result<Vec<u8>> {
     // iv_2:    69474772AF6FDAB342743AEFAA186287
     const iV: [u8; 16] = [105, 71, 71, 114, 175, 111, 218, 179, 66, 116, 58, 239, 170, 24, 98, 135];
     aes_encrypt(d1, &key, &iV)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::FromHex;
+    use disc::tests::noise;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        FromHex::from_hex(hex.as_bytes().to_owned()).unwrap()
+    }
+
+    // NIST SP 800-38A, F.2.2 CBC-AES128.Decrypt
+    const NIST_KEY: &str = "2B7E151628AED2A6ABF7158809CF4F3C";
+    const NIST_IV: &str = "000102030405060708090A0B0C0D0E0F";
+    const NIST_CIPHERTEXT: &str = "7649ABAC8119B246CEE98E9B12E9197D5086CB9B507219EE95DB113A917678B273BED6B8E3C1743B7116E69E222295163FF1CAA1681FAC09120ECA307586E1A7";
+    const NIST_PLAINTEXT: &str = "6BC1BEE22E409F96E93D7E117393172AAE2D8A571E03AC9C9EB76FAC45AF8E5130C81C46A35CE411E5FBC1191A0A52EFF69F2445DF4F9B17AD2B417BE66C3710";
+
+    #[test]
+    fn nist_cbc_decrypt() {
+        let mut data = from_hex(NIST_CIPHERTEXT);
+        aes_decrypt_in_place(&mut data, &from_hex(NIST_KEY), &from_hex(NIST_IV)).unwrap();
+        assert_eq!(data, from_hex(NIST_PLAINTEXT));
+    }
+
+    #[test]
+    fn nist_cbc_decrypt_without_aesni() {
+        let mut data = from_hex(NIST_CIPHERTEXT);
+        cbc_decrypt_in_place(&aessafe::AesSafe128Decryptor::new(&from_hex(NIST_KEY)), &mut data, &from_hex(NIST_IV));
+        assert_eq!(data, from_hex(NIST_PLAINTEXT));
+    }
+
+    #[test]
+    fn nist_cbc_encrypt() {
+        assert_eq!(aes_encrypt(&from_hex(NIST_PLAINTEXT), &from_hex(NIST_KEY), &from_hex(NIST_IV)).unwrap(), from_hex(NIST_CIPHERTEXT));
+    }
+
+    #[test]
+    fn encrypt_decrypt_round_trip() {
+        let key = noise(16, 1);
+        let iv = noise(16, 2);
+        let plain = noise(2048 * 3, 3);
+        let encrypted = aes_encrypt(&plain, &key, &iv).unwrap();
+        assert_ne!(encrypted, plain);
+        let mut decrypted = encrypted.clone();
+        aes_decrypt_in_place(&mut decrypted, &key, &iv).unwrap();
+        assert_eq!(decrypted, plain);
+        assert_eq!(aes_decrypt(&encrypted, &key, &iv).unwrap(), plain);
+    }
+
+    #[test]
+    fn rejects_partial_blocks_and_bad_keys() {
+        assert!(aes_decrypt_in_place(&mut [0u8; 20], &[0u8; 16], &[0u8; 16]).is_err());
+        assert!(aes_decrypt_in_place(&mut [0u8; 32], &[0u8; 15], &[0u8; 16]).is_err());
+        assert!(aes_decrypt_in_place(&mut [0u8; 32], &[0u8; 16], &[0u8; 17]).is_err());
+    }
+}
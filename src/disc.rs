@@ -6,8 +6,11 @@
 use super::errors::*;
 use sector::{Region, VecRegion};
 use std::io::{Read, Seek, SeekFrom};
+use std::cmp;
 use decrypt;
+use cache::{SectorCache, READ_AHEAD_SECTORS};
 use ird::IRDFile;
+use sfb::DiscMetadata;
 
 /// given a four-element &[u8], calculate the big-endian u32 that they represent
 /// shamelessly taken out of nom
@@ -16,6 +19,53 @@ fn be_u32(i: &[u8]) -> u32 {
     ((i[0] as u32) << 24) + ((i[1] as u32) << 16) + ((i[2] as u32) << 8) + i[3] as u32
 }
 
+/// Offset of the 3k3y-injected data within sector 1
+///
+/// This is the tagline, followed by the d1 and d2 keys, and runs to the end of the sector.
+pub const OFFSET_3K3Y: usize = 0xF70 - 2048;
+
+/// Zero out the 3k3y-injected data in sector 1, giving back what was on the original disc
+pub fn strip_3k3y(sector1: &mut [u8]) {
+    for byte in &mut sector1[OFFSET_3K3Y..2048] {
+        *byte = 0;
+    }
+}
+
+/// Inject 3k3y data into sector 1, the reverse of `strip_3k3y`
+///
+/// The tagline says whether the rest of the image is encrypted or not,
+/// and the d1 and d2 keys come from the disc's IRD file.
+pub fn inject_3k3y(sector1: &mut [u8], decrypted: bool, d1: &[u8; 16], d2: &[u8; 16]) {
+    let tagline: &[u8; 16] = if decrypted { b"Decrypted 3K BLD" } else { b"Encrypted 3K BLD" };
+    strip_3k3y(sector1);
+    sector1[OFFSET_3K3Y..(OFFSET_3K3Y+16)].copy_from_slice(tagline);
+    sector1[(OFFSET_3K3Y+16)..(OFFSET_3K3Y+32)].copy_from_slice(d1);
+    sector1[(OFFSET_3K3Y+32)..(OFFSET_3K3Y+48)].copy_from_slice(d2);
+}
+
+/// AES iV for a sector, which is just the sector number as a big-endian number
+///
+/// Courtesy of the PS3DevWiki.
+fn sector_iv(sector: u32) -> [u8; 16] {
+    let mut iv = [0u8; 16];
+    iv[12] = (sector >> 24) as u8;
+    iv[13] = (sector >> 16) as u8;
+    iv[14] = (sector >> 8) as u8;
+    iv[15] = sector as u8;
+    iv
+}
+
+/// Patch the 3k3y tagline in sector 1 (the second sector, 0 indexed) from encrypted to decrypted
+///
+/// This is mainly just so we get byte-for-byte identical decrypts.
+/// Encrypted taglines: Encrypted 3K ___
+/// Decrypted taglines: Decrypted 3K ___
+/// so we just change the "En" to "De"
+fn patch_3k3y_tagline(sector1: &mut [u8]) {
+    sector1[1904] = b'D';
+    sector1[1905] = b'e';
+}
+
 /// Wrapped PS3 disc
 ///
 /// Using `read_sector`, will transparently decrypt sectors as needed.
@@ -35,6 +85,15 @@ pub struct PS3Disc<F> {
     ///
     /// If this is present, it will be automatically rewritten to "Decrypted" when sector 1 (the second sector) is read
     pub tagline_3k3y: Option<String>,
+    /// Whether the image has already been decrypted
+    ///
+    /// If this is set, `read_sector` won't try to decrypt anything.
+    /// It's detected from a "Decrypted 3K" tagline, otherwise set it with `set_decrypted`.
+    pub decrypted: bool,
+    /// Disc-level metadata from PS3_DISC.SFB, once `metadata` has read it
+    metadata: Option<DiscMetadata>,
+    /// Decrypted sectors kept around for `read_sector` and friends, if there's a cache
+    cache: Option<SectorCache>,
     /// File handle used to read the disc
     reader_handle: F
 }
@@ -55,6 +114,37 @@ pub struct PS3DiscDecryptor {
     pub has_3k3y_tagline: bool
 }
 
+/// A run of sectors a read didn't find in the cache, see `PS3Disc::read_cache_misses`
+pub struct CacheMiss {
+    /// First sector of the run
+    pub first: u32,
+    /// How many sectors of the run were asked for
+    pub count: u32,
+    /// The run's sectors, plus any read ahead after them
+    pub data: Vec<u8>
+}
+
+impl CacheMiss {
+    /// Decrypt the run in place with `decrypt`, then copy the sectors that were asked for into `buf`
+    ///
+    /// `buf` is the whole read, starting at sector `start`. `decrypt` gets the first sector
+    /// and the sectors to decrypt, like `PS3DiscDecryptor::decrypt_sectors_in_place` with the arguments swapped.
+    /// If the read-ahead doesn't decrypt, it's dropped rather than failing the read.
+    pub fn decrypt_into<D: Fn(u32, &mut [u8]) -> Result<()>>(&mut self, start: u32, buf: &mut [u8], decrypt: D) -> Result<()> {
+        let len = self.count as usize * 2048;
+        let read_ahead_ok = {
+            let (wanted, read_ahead) = self.data.split_at_mut(len);
+            decrypt(self.first, wanted)?;
+            read_ahead.is_empty() || decrypt(self.first + self.count, read_ahead).is_ok()
+        };
+        if !read_ahead_ok {
+            self.data.truncate(len);
+        }
+        let offset = (self.first - start) as usize * 2048;
+        buf[offset..(offset + len)].copy_from_slice(&self.data[..len]);
+        Ok(())
+    }
+}
 
 impl<F: Read+Seek> PS3Disc<F> {
     /// Create a new PS3Disc
@@ -86,6 +176,9 @@ impl<F: Read+Seek> PS3Disc<F> {
             Some(String::from_utf8_lossy(f70).to_string())
         };
 
+        // 3k3y images that have been decrypted say so in the tagline
+        let decrypted = tagline_3k3y.as_ref().map_or(false, |t| t.starts_with("Decrypted"));
+
         // Get the game ID and remove the space padding
         let game_id = String::from_utf8_lossy(&header[2064..(2064+20)]);
         let game_id = game_id.trim_right();
@@ -125,52 +218,119 @@ impl<F: Read+Seek> PS3Disc<F> {
         }
 
         Ok(PS3Disc {
-            regions, d1, disc_key, tagline_3k3y,
+            regions, d1, disc_key, tagline_3k3y, decrypted,
             total_sectors: start_sector+1,
             gameid: game_id.to_string(),
+            metadata: None,
+            cache: None,
             reader_handle: handle
         })
     }
 
+    /// Disc-level metadata from PS3_DISC.SFB
+    ///
+    /// It's read the first time it's asked for and kept after that.
+    /// PS3_DISC.SFB is in the unencrypted part of the disc, so this usually works without a key.
+    pub fn metadata(&mut self) -> Result<&DiscMetadata> {
+        if self.metadata.is_none() {
+            let metadata = DiscMetadata::read(self)?;
+            self.metadata = Some(metadata);
+        }
+        Ok(self.metadata.as_ref().unwrap())
+    }
+
     /// Read a sector, automatically decrypting if needed
     ///
     /// Remember that sector is 0 indexed, so the first sector is #0.
-    #[allow(non_snake_case)]
-    #[allow(identity_op)]
     pub fn read_sector(&mut self, sector: u32) -> Result<Vec<u8>> {
         let mut buf = [0u8; 2048];
-        self.reader_handle.seek(SeekFrom::Start((sector as u64)*2048))
-            .chain_err(|| "failed to seek")?;
-        self.reader_handle.read_exact(&mut buf).chain_err(|| "failed to read")?;
+        self.read_sector_into(sector, &mut buf)?;
+        Ok(buf.to_vec())
+    }
 
-        if self.regions.region_for_sector(sector).unwrap().encrypted {
-            if let Some(disc_key) = self.disc_key {
-                // code courtesy of the PS3DevWiki.
-                let mut iV = [0u8; 16];
-                iV[12] = ((sector & 0xFF000000) >> 24) as u8;
-                iV[13] = ((sector & 0x00FF0000) >> 16) as u8;
-                iV[14] = ((sector & 0x0000FF00) >> 8) as u8;
-                iV[15] = ((sector & 0x000000FF) >> 0) as u8;
-                decrypt::aes_decrypt(&buf, &disc_key, &iV)
-            } else {
-                bail!("Encountered an encrypted sector but can't decrypt!");
+    /// Read a sector into `buf`, automatically decrypting if needed
+    ///
+    /// Same as `read_sector`, without allocating.
+    pub fn read_sector_into(&mut self, sector: u32, buf: &mut [u8; 2048]) -> Result<()> {
+        self.read_sectors_into(sector, buf)
+    }
+
+    /// Read a run of sectors into `buf`, automatically decrypting if needed
+    ///
+    /// `buf` has to be a whole number of sectors long, and that many are read starting at `start`.
+    /// If there's a cache, sectors come out of it when they can and go into it when they can't.
+    pub fn read_sectors_into(&mut self, start: u32, buf: &mut [u8]) -> Result<()> {
+        let mut misses = self.read_cache_misses(start, buf)?;
+        for miss in &mut misses {
+            miss.decrypt_into(start, buf, |first, data| self.decrypt_in_place(first, data))?;
+            self.add_to_cache(miss.first, &miss.data);
+        }
+        Ok(())
+    }
+
+    /// Copy whatever's cached of a read into `buf`, and read the rest off the disc without decrypting it
+    ///
+    /// Each run of sectors that wasn't cached is read in one go, along with a few more after it
+    /// if there's a cache to put them in (see `READ_AHEAD_SECTORS`).
+    /// Finish the read with `CacheMiss::decrypt_into`, then `add_to_cache`.
+    pub fn read_cache_misses(&mut self, start: u32, buf: &mut [u8]) -> Result<Vec<CacheMiss>> {
+        if buf.len() % 2048 != 0 {
+            bail!("PS3 disc sectors are always exactly 2048 bytes. No partial reads.");
+        }
+        let mut misses: Vec<CacheMiss> = vec![];
+        for (i, data) in buf.chunks_mut(2048).enumerate() {
+            let sector = start + i as u32;
+            let cached = match self.cache {
+                Some(ref mut cache) => cache.get(sector, data),
+                None => false
+            };
+            if cached {
+                continue;
             }
-        } else {
-            if sector == 1 && self.tagline_3k3y.is_some() {
-                // Patch the 3k3y tagline if it exists
-                // at the end of the second sector (0 indexed so it's sector 1)
-                // mainly just so we get byte-for-byte identical decrypts
-                // Encrypted taglines: Encrypted 3K ___
-                // Decrypted taglines: Decrypted 3K ___
-                // so we just change the "En" to "De"
-                buf[1904] = b'D';
-                buf[1905] = b'e';
+            if let Some(miss) = misses.last_mut() {
+                if miss.first + miss.count == sector {
+                    miss.count += 1;
+                    continue;
+                }
             }
+            misses.push(CacheMiss { first: sector, count: 1, data: vec![] });
+        }
 
-            Ok(buf.to_vec())
+        let read_ahead = if self.cache.is_some() { READ_AHEAD_SECTORS } else { 0 };
+        for miss in &mut misses {
+            let end = miss.first + miss.count;
+            let read_ahead_end = cmp::max(cmp::min(end + read_ahead, self.total_sectors), end);
+            miss.data = vec![0u8; (read_ahead_end - miss.first) as usize * 2048];
+            if read_ahead_end > end && self.read_sectors_raw_into(miss.first, &mut miss.data).is_ok() {
+                continue;
+            }
+            // The extra sectors might be what's failing, so go again with just what was asked for
+            miss.data.truncate(miss.count as usize * 2048);
+            self.read_sectors_raw_into(miss.first, &mut miss.data)?;
         }
+        Ok(misses)
     }
 
+    /// Decrypt sectors read by `read_sectors_raw_into` in place, as needed
+    fn decrypt_in_place(&self, start: u32, buf: &mut [u8]) -> Result<()> {
+        for (i, data) in buf.chunks_mut(2048).enumerate() {
+            let sector = start + i as u32;
+            let encrypted = match self.regions.region_for_sector(sector) {
+                Some(region) => region.encrypted,
+                None => bail!("Sector {} isn't in any of the disc's regions", sector)
+            };
+            if !self.decrypted && encrypted {
+                if let Some(disc_key) = self.disc_key {
+                    decrypt::aes_decrypt_in_place(data, &disc_key, &sector_iv(sector))?;
+                } else {
+                    bail!("Encountered an encrypted sector but can't decrypt!");
+                }
+            } else if sector == 1 && self.tagline_3k3y.is_some() {
+                patch_3k3y_tagline(data);
+            }
+        }
+        Ok(())
+    }
 
     /// Read a sector, but don't automatically decrypt
     ///
@@ -183,6 +343,20 @@ impl<F: Read+Seek> PS3Disc<F> {
         Ok(buf.to_vec())
     }
 
+    /// Read a run of sectors into `buf` without decrypting them
+    ///
+    /// `buf` has to be a whole number of sectors long, and that many are read starting at `sector`.
+    /// Nothing is allocated, so it's good for reading big chunks to decrypt with
+    /// `PS3DiscDecryptor::decrypt_sectors_in_place`.
+    pub fn read_sectors_raw_into(&mut self, sector: u32, buf: &mut [u8]) -> Result<()> {
+        if buf.len() % 2048 != 0 {
+            bail!("PS3 disc sectors are always exactly 2048 bytes. No partial reads.");
+        }
+        self.reader_handle.seek(SeekFrom::Start((sector as u64)*2048))
+            .chain_err(|| "failed to seek")?;
+        self.reader_handle.read_exact(buf).chain_err(|| "failed to read")
+    }
+
     /// Returns a standalone struct that can be used to decrypt individual sectors.
     ///
     /// See struct documentation for more information.
@@ -213,6 +387,7 @@ impl<F: Read+Seek> PS3Disc<F> {
         disc_key_arr.copy_from_slice(disc_key.as_slice());
         self.d1 = Some(d1_arr);
         self.disc_key = Some(disc_key_arr);
+        self.clear_cache();
         Ok(())
     }
 
@@ -226,9 +401,44 @@ impl<F: Read+Seek> PS3Disc<F> {
         let mut disc_key_arr = [0u8; 16];
         disc_key_arr.copy_from_slice(disc_key);
         self.disc_key = Some(disc_key_arr);
+        self.clear_cache();
         Ok(())
     }
 
+    /// Mark the image as already decrypted, so `read_sector` passes sectors through untouched
+    pub fn set_decrypted(&mut self, decrypted: bool) {
+        self.decrypted = decrypted;
+        self.clear_cache();
+    }
+
+    /// Keep decrypted sectors in a cache, so reading them again doesn't need the drive
+    ///
+    /// Changing the key or `set_decrypted` afterwards empties it, since the sectors in it would be wrong.
+    pub fn set_cache(&mut self, cache: SectorCache) {
+        self.cache = Some(cache);
+    }
+
+    /// Forget any cached sectors, since they were decrypted with a different key or setting
+    fn clear_cache(&mut self) {
+        if let Some(ref mut cache) = self.cache {
+            cache.clear();
+        }
+    }
+
+    /// The sector cache, if there is one, mainly for its hit and miss counts
+    pub fn cache(&self) -> Option<&SectorCache> {
+        self.cache.as_ref()
+    }
+
+    /// Add a run of decrypted sectors, starting at `start`, to the cache if there is one
+    pub fn add_to_cache(&mut self, start: u32, data: &[u8]) {
+        if let Some(ref mut cache) = self.cache {
+            for (i, sector) in data.chunks(2048).enumerate() {
+                cache.insert(start + i as u32, sector);
+            }
+        }
+    }
+
     /// Returns whether or not this PS3Disc is prepared to decrypt sectors
     pub fn can_decrypt(&self) -> bool {
         self.disc_key.is_some()
@@ -256,34 +466,246 @@ impl PS3DiscDecryptor {
     /// `ps3discdecryptor.decrypt_sector(ps3disc.read_sector_raw(4), 4)`
     /// is functionally identical to
     /// `ps3disc.read_sector(4)`
-    #[allow(non_snake_case)]
-    #[allow(identity_op)]
     pub fn decrypt_sector(&self, buf: &[u8], sector: u32) -> Result<Vec<u8>> {
+        let mut buf = buf.to_owned();
+        self.decrypt_sector_in_place(&mut buf, sector)?;
+        Ok(buf)
+    }
+
+    /// Decrypt a sector in place, same as `decrypt_sector` without allocating
+    pub fn decrypt_sector_in_place(&self, buf: &mut [u8], sector: u32) -> Result<()> {
         if buf.len() != 2048 {
             bail!("PS3 disc sectors are always exactly 2048 bytes. No partial decrypts.");
         }
-        if self.regions.region_for_sector(sector).unwrap().encrypted { //TODO fix this
-            // code courtesy of the PS3DevWiki.
-            let mut iV = [0u8; 16];
-            iV[12] = ((sector & 0xFF000000)>>24) as u8;
-            iV[13] = ((sector & 0x00FF0000)>>16) as u8;
-            iV[14] = ((sector & 0x0000FF00)>> 8) as u8;
-            iV[15] = ((sector & 0x000000FF)>> 0) as u8;
-            decrypt::aes_decrypt(buf, &self.disc_key, &iV)
+        let encrypted = match self.regions.region_for_sector(sector) {
+            Some(region) => region.encrypted,
+            None => bail!("Sector {} isn't in any of the disc's regions", sector)
+        };
+        if encrypted {
+            decrypt::aes_decrypt_in_place(buf, &self.disc_key, &sector_iv(sector))
+        } else {
+            if sector == 1 && self.has_3k3y_tagline {
+                patch_3k3y_tagline(buf);
+            }
+            Ok(())
+        }
+    }
+
+    /// Decrypt a run of sectors in place, starting at sector number `first_sector`
+    ///
+    /// Same result as calling `decrypt_sector` on each sector in `buf`, without allocating.
+    pub fn decrypt_sectors_in_place(&self, buf: &mut [u8], first_sector: u32) -> Result<()> {
+        if buf.len() % 2048 != 0 {
+            bail!("PS3 disc sectors are always exactly 2048 bytes. No partial decrypts.");
+        }
+        for (i, data) in buf.chunks_mut(2048).enumerate() {
+            self.decrypt_sector_in_place(data, first_sector + i as u32)?;
+        }
+        Ok(())
+    }
+
+    /// Standalone sector encryption function, the reverse of `decrypt_sector`
+    ///
+    /// `ps3discdecryptor.encrypt_sector(&ps3discdecryptor.decrypt_sector(buf, 4)?, 4)`
+    /// gives back `buf`, so a decrypted image can be turned back into the original dump.
+    pub fn encrypt_sector(&self, buf: &[u8], sector: u32) -> Result<Vec<u8>> {
+        if buf.len() != 2048 {
+            bail!("PS3 disc sectors are always exactly 2048 bytes. No partial encrypts.");
+        }
+        let encrypted = match self.regions.region_for_sector(sector) {
+            Some(region) => region.encrypted,
+            None => bail!("Sector {} isn't in any of the disc's regions", sector)
+        };
+        if encrypted {
+            decrypt::aes_encrypt(buf, &self.disc_key, &sector_iv(sector))
         } else {
             let mut buf = buf.to_owned();
             if sector == 1 && self.has_3k3y_tagline {
-                // Patch the 3k3y tagline if it exists
-                // at the end of the second sector (0 indexed so it's sector 1)
-                // mainly just so we get byte-for-byte identical decrypts
-                // Encrypted taglines: Encrypted 3K ___
-                // Decrypted taglines: Decrypted 3K ___
-                // so we just change the "En" to "De"
-                buf[1904] = b'D';
-                buf[1905] = b'e';
+                // Undo the tagline patch from decrypting, "De" back to "En"
+                buf[1904] = b'E';
+                buf[1905] = b'n';
             }
 
             Ok(buf)
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Disc key for `test_image`
+    pub const TEST_DISC_KEY: [u8; 16] = [0x7C, 0x14, 0x16, 0xBA, 0x2F, 0x4A, 0xE2, 0xC9, 0x27, 0x1C, 0xFB, 0x41, 0xBE, 0x83, 0xE5, 0x2C];
+
+    /// Bytes that look random enough to stand in for encrypted sectors
+    pub fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    /// Two unencrypted sectors, four encrypted, then two more unencrypted
+    fn decryptor() -> PS3DiscDecryptor {
+        PS3DiscDecryptor {
+            regions: vec![
+                Region { id: 0, start: 0, end: 1, encrypted: false },
+                Region { id: 1, start: 2, end: 5, encrypted: true },
+                Region { id: 2, start: 6, end: 7, encrypted: false }
+            ],
+            disc_key: TEST_DISC_KEY,
+            has_3k3y_tagline: true
+        }
+    }
+
+    fn image() -> Vec<u8> {
+        let mut image = noise(8 * 2048, 42);
+        image[2048 + 1904] = b'E';
+        image[2048 + 1905] = b'n';
+        image
+    }
+
+    /// A made up 3k3y image, 40 sectors long
+    ///
+    /// Sectors 0 and 1 are the header, 2 to 29 are encrypted and 30 to 39 aren't.
+    /// It's all noise other than the header, and there's no d1 key, so use `TEST_DISC_KEY`.
+    pub fn test_image() -> Vec<u8> {
+        test_image_with_regions(&[0, 1, 30, 39])
+    }
+
+    /// `test_image`, but with the unencrypted regions bounded by `bounds`, in pairs of first and last sector
+    ///
+    /// Everything between them is encrypted, and the disc ends at the last bound.
+    pub fn test_image_with_regions(bounds: &[u32]) -> Vec<u8> {
+        let total_sectors = *bounds.last().unwrap() as usize + 1;
+        let mut image = noise(total_sectors * 2048, 7);
+        for b in &mut image[..4096] {
+            *b = 0;
+        }
+        image[3] = (bounds.len() / 2) as u8;
+        for (i, bound) in bounds.iter().enumerate() {
+            for j in 0..4 {
+                image[8 + i * 4 + j] = (bound >> (24 - j * 8)) as u8;
+            }
+        }
+        image[2048..(2048 + 12)].copy_from_slice(b"PlayStation3");
+        image[2064..(2064 + 20)].copy_from_slice(b"BLUS-99999          ");
+        image[0xF70..(0xF70 + 16)].copy_from_slice(b"Encrypted 3K BLD");
+        image
+    }
+
+    /// Put decrypted `data` into a test image starting at `sector`, encrypting it if it's in an encrypted region
+    ///
+    /// The last sector is padded out with zeros.
+    pub fn write_sectors(image: &mut [u8], sector: u32, data: &[u8]) {
+        let decryptor = test_disc(image.to_vec()).get_decryptor().unwrap();
+        for (i, chunk) in data.chunks(2048).enumerate() {
+            let mut buf = [0u8; 2048];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            let sector = sector + i as u32;
+            let encrypted = decryptor.encrypt_sector(&buf, sector).unwrap();
+            image[(sector as usize * 2048)..((sector + 1) as usize * 2048)].copy_from_slice(&encrypted);
+        }
+    }
+
+    /// `test_image`, or whatever's passed in, as a disc ready to decrypt
+    pub fn test_disc(image: Vec<u8>) -> PS3Disc<Cursor<Vec<u8>>> {
+        let mut disc = PS3Disc::new(Cursor::new(image)).unwrap();
+        disc.set_disc_key(&TEST_DISC_KEY).unwrap();
+        disc
+    }
+
+    #[test]
+    fn parses_test_image() {
+        let disc = test_disc(test_image());
+        assert_eq!(disc.total_sectors, 40);
+        assert_eq!(disc.gameid, "BLUS-99999");
+        assert_eq!(disc.tagline_3k3y, Some("Encrypted 3K BLD".to_string()));
+        assert_eq!(disc.regions.iter().map(|r| (r.start, r.end, r.encrypted)).collect::<Vec<_>>(),
+                   vec![(0, 1, false), (2, 29, true), (30, 39, false)]);
+    }
+
+    #[test]
+    fn changing_the_key_clears_the_cache() {
+        let mut expected = vec![0u8; 4 * 2048];
+        test_disc(test_image()).read_sectors_into(2, &mut expected).unwrap();
+
+        let mut disc = test_disc(test_image());
+        disc.set_cache(SectorCache::with_size(1024 * 1024));
+        disc.set_disc_key(&[0u8; 16]).unwrap();
+        let mut buf = vec![0u8; 4 * 2048];
+        disc.read_sectors_into(2, &mut buf).unwrap();
+        assert_ne!(buf, expected);
+
+        disc.set_disc_key(&TEST_DISC_KEY).unwrap();
+        disc.read_sectors_into(2, &mut buf).unwrap();
+        assert_eq!(buf, expected);
+
+        disc.set_decrypted(true);
+        disc.read_sectors_into(2, &mut buf).unwrap();
+        assert_eq!(&buf[..], &test_image()[(2 * 2048)..(6 * 2048)]);
+    }
+
+    #[test]
+    fn read_ahead_that_fails_to_decrypt_is_dropped() {
+        let mut miss = CacheMiss { first: 3, count: 2, data: vec![1u8; 6 * 2048] };
+        let mut buf = vec![0u8; 4 * 2048];
+        miss.decrypt_into(2, &mut buf, |first, data| {
+            if first > 3 {
+                bail!("not this one");
+            }
+            for b in data.iter_mut() {
+                *b = 2;
+            }
+            Ok(())
+        }).unwrap();
+        assert_eq!(miss.data, vec![2u8; 2 * 2048]);
+        assert_eq!(&buf[..2048], &[0u8; 2048][..]);
+        assert_eq!(&buf[2048..(3 * 2048)], &vec![2u8; 2 * 2048][..]);
+
+        // The sectors that were asked for still have to decrypt though
+        let mut miss = CacheMiss { first: 3, count: 2, data: vec![1u8; 6 * 2048] };
+        assert!(miss.decrypt_into(3, &mut buf, |_, _| bail!("nope")).is_err());
+    }
+
+    #[test]
+    fn decrypt_sectors_matches_decrypt_sector() {
+        let decryptor = decryptor();
+        let image = image();
+        let mut expected = vec![];
+        for (sector, data) in image.chunks(2048).enumerate() {
+            expected.extend(decryptor.decrypt_sector(data, sector as u32).unwrap());
+        }
+        assert_eq!(&expected[(2048 + 1904)..(2048 + 1906)], b"De");
+        assert_eq!(&expected[(6 * 2048)..], &image[(6 * 2048)..]);
+
+        let mut all = image.clone();
+        decryptor.decrypt_sectors_in_place(&mut all, 0).unwrap();
+        assert_eq!(all, expected);
+
+        // Starting partway through, each sector still gets its own IV
+        let mut part = image[(3 * 2048)..(7 * 2048)].to_vec();
+        decryptor.decrypt_sectors_in_place(&mut part, 3).unwrap();
+        assert_eq!(&part[..], &expected[(3 * 2048)..(7 * 2048)]);
+    }
+
+    #[test]
+    fn encrypt_sector_undoes_decrypt_sector() {
+        let decryptor = decryptor();
+        let image = image();
+        for (sector, data) in image.chunks(2048).enumerate() {
+            let decrypted = decryptor.decrypt_sector(data, sector as u32).unwrap();
+            assert_eq!(&decryptor.encrypt_sector(&decrypted, sector as u32).unwrap()[..], data);
+        }
+    }
+
+    #[test]
+    fn sectors_outside_the_regions_are_errors() {
+        let mut buf = vec![0u8; 2048];
+        assert!(decryptor().decrypt_sector_in_place(&mut buf, 8).is_err());
+        assert!(decryptor().decrypt_sectors_in_place(&mut vec![0u8; 2 * 2048], 7).is_err());
+    }
+}
@@ -1,8 +1,9 @@
-use nom::{be_u8, le_u32, le_i32, le_i64};
+use nom::{be_u8, le_u32, le_i32, le_i64, IResult, ErrorKind as NomErrorKind, Err as NomErr};
 use super::errors::*;
 use std::path::Path;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{self, BufReader, Read};
+use flate2::Crc;
 use flate2::bufread::GzDecoder;
 
 #[derive(Debug, Clone)]
@@ -20,40 +21,77 @@ pub struct IRDFile {
     pub pic_data: Vec<u8>, //TODO: switch to [u8; 0x73] when practical, Rust 1.21?
     pub data1: [u8; 16],
     pub data2: [u8; 16],
-    pub unique_identifier: u32,
-    pub crc32: [u8; 4]
+    /// Not present in IRDv6 files
+    pub unique_identifier: Option<u32>,
+    /// CRC32 of the decompressed IRD file, minus these last four bytes
+    pub crc32: u32
 }
 
+/// Oldest IRD version we know how to parse
+pub const IRD_MIN_VERSION: u8 = 6;
+/// Newest IRD version we know how to parse
+pub const IRD_MAX_VERSION: u8 = 9;
+
+/// Names of the fields in an IRD file, used to report which one failed to parse
+///
+/// The index into this is used as the nom custom error code.
+const IRD_FIELDS: [&str; 23] = [
+    "magic", "version", "game_id", "game_name length", "game_name", "update_ver", "game_ver", "app_ver",
+    "v7 unique_identifier", "header length", "header", "footer length", "footer", "region_hashes",
+    "file_hashes", "padding", "v9 pic_data", "data1", "data2", "pic_data", "unique_identifier", "crc32",
+    "unknown"
+];
+
+/// Get the nom custom error code for a field name
+fn field_code(field: &str) -> u32 {
+    IRD_FIELDS.iter().position(|&f| f == field).unwrap_or(IRD_FIELDS.len()-1) as u32
+}
+
+/// Wraps an IRD field parser so that any failure, including running out of input,
+/// gets reported as a nom error tagged with the field's name.
+macro_rules! ird_field (
+    ($i:expr, $field:expr, $submac:ident!( $($args:tt)* )) => (
+        add_return_error!($i, NomErrorKind::Custom(field_code($field)), complete!($submac!($($args)*)))
+    );
+    ($i:expr, $field:expr, $f:expr) => (
+        ird_field!($i, $field, call!($f))
+    );
+);
+
 // This is a hack because do_parse doesn't allow types
 // and rustc can't seem to infer Vec<[u8; 16]> from length_count!(be_u8, count_fixed!(u8, be_u8, 16))
 // So, we split it off. (and it looks nicer I guess)
 named!(u8_16<[u8; 16]>, count_fixed!(u8, be_u8, 16));
 
 named!(pub parse_ird<IRDFile>, do_parse!(
-    tag!("3IRD") >>
-    version: be_u8 >>
-    game_id: take_str!(9) >>
-    namelen: be_u8 >>
-    game_name: take_str!(namelen) >>
-    update_ver: take_str!(4) >>
-    game_ver: take_str!(5) >>
-    app_ver: take_str!(5) >>
-    //TODO: ver==7 unique ID needs to go here
-    headerlen: le_u32 >>
-    header_comp: take!(headerlen) >>
-    footerlen: le_u32 >>
-    footer_comp: take!(footerlen) >>
-    region_hashes: length_count!(be_u8, u8_16) >> //region MD5 hashes
-    file_hashes: length_count!(le_i32, tuple!(le_i64, u8_16)) >> // file MD5 hashes, thrown out for now.
-    take!(4) >> //no joke, there's literally two ReadUInt16()s in a row here that don't feed to anything.
-    pic_data: take!(0x73) >> //TODO: version gate behind ver>=9
-    data1: u8_16 >>
-    data2: u8_16 >>
-    //TODO: pic data here for ver<9
-    unique_identifier: le_u32 >> //TODO: version gate this behind ver>7
-    crc32: count_fixed!(u8, be_u8, 4) >> //TODO verify crc32
+    ird_field!("magic", tag!("3IRD")) >>
+    version: ird_field!("version", verify!(be_u8, |v: u8| v >= IRD_MIN_VERSION && v <= IRD_MAX_VERSION)) >>
+    game_id: ird_field!("game_id", take_str!(9)) >>
+    namelen: ird_field!("game_name length", be_u8) >>
+    game_name: ird_field!("game_name", take_str!(namelen)) >>
+    update_ver: ird_field!("update_ver", take_str!(4)) >>
+    game_ver: ird_field!("game_ver", take_str!(5)) >>
+    app_ver: ird_field!("app_ver", take_str!(5)) >>
+    // v7 only, later versions moved it to the end
+    unique_identifier_v7: ird_field!("v7 unique_identifier", cond!(version == 7, le_u32)) >>
+    headerlen: ird_field!("header length", le_u32) >>
+    header_comp: ird_field!("header", take!(headerlen)) >>
+    footerlen: ird_field!("footer length", le_u32) >>
+    footer_comp: ird_field!("footer", take!(footerlen)) >>
+    region_hashes: ird_field!("region_hashes", length_count!(be_u8, u8_16)) >> //region MD5 hashes
+    file_hashes: ird_field!("file_hashes", length_count!(le_i32, tuple!(le_i64, u8_16))) >> // file MD5 hashes
+    //no joke, there's literally two ReadUInt16()s in a row here that don't feed to anything.
+    ird_field!("padding", take!(4)) >>
+    // v9 moved the pic data in front of the keys
+    pic_data_v9: ird_field!("v9 pic_data", cond!(version >= 9, take!(0x73))) >>
+    data1: ird_field!("data1", u8_16) >>
+    data2: ird_field!("data2", u8_16) >>
+    pic_data_old: ird_field!("pic_data", cond!(version < 9, take!(0x73))) >>
+    unique_identifier_v8: ird_field!("unique_identifier", cond!(version >= 8, le_u32)) >>
+    // checked in read_ird, since we need the whole buffer for that
+    crc32: ird_field!("crc32", le_u32) >>
     (IRDFile {
-        version, data1, data2, unique_identifier, crc32,
+        version, data1, data2, crc32,
         game_id: game_id.to_string(),
         game_name: game_name.to_string(),
         update_ver: update_ver.to_string(),
@@ -63,17 +101,355 @@ named!(pub parse_ird<IRDFile>, do_parse!(
         footer_comp: footer_comp.to_owned(),
         file_hashes: file_hashes.to_owned(),
         region_hashes: region_hashes.to_owned(),
-        pic_data: pic_data.to_owned()
+        pic_data: pic_data_v9.or(pic_data_old).unwrap_or_default().to_owned(),
+        unique_identifier: unique_identifier_v7.or(unique_identifier_v8)
     })
 ));
 
+impl IRDFile {
+    /// Decompress the header, which is the start of the original disc image
+    ///
+    /// This covers sector 0 onwards, up to the first file on the disc,
+    /// so it contains the region table and the filesystem.
+    pub fn header(&self) -> Result<Vec<u8>> {
+        decompress(self.header_comp.as_ref()).chain_err(|| "Failed to decompress IRD header")
+    }
+
+    /// Decompress the footer, which is the end of the original disc image
+    pub fn footer(&self) -> Result<Vec<u8>> {
+        decompress(self.footer_comp.as_ref()).chain_err(|| "Failed to decompress IRD footer")
+    }
+
+    /// Number of disc sectors contained in the header
+    pub fn header_sectors(&self) -> Result<u32> {
+        decompressed_sectors(self.header_comp.as_ref()).chain_err(|| "Failed to decompress IRD header")
+    }
+
+    /// Number of disc sectors contained in the footer
+    pub fn footer_sectors(&self) -> Result<u32> {
+        decompressed_sectors(self.footer_comp.as_ref()).chain_err(|| "Failed to decompress IRD footer")
+    }
+}
+
+/// Decompress one of the gzipped blobs inside an IRD file
+fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    GzDecoder::new(data).read_to_end(&mut buf).chain_err(|| "gzip decompression failed")?;
+    Ok(buf)
+}
+
+/// Find how many sectors a gzipped blob inside an IRD file decompresses to, without keeping it around
+fn decompressed_sectors(data: &[u8]) -> Result<u32> {
+    let len = io::copy(&mut GzDecoder::new(data), &mut io::sink()).chain_err(|| "gzip decompression failed")?;
+    if len % 2048 != 0 {
+        bail!("expected a whole number of sectors, got {} bytes", len);
+    }
+    Ok((len / 2048) as u32)
+}
+
 pub fn read_ird<P: AsRef<Path>>(fpath: P) -> Result<IRDFile> {
     let f = File::open(fpath).chain_err(|| "Failed to open IRD file")?;
     let reader = BufReader::new(f);
     let mut reader = GzDecoder::new(reader);
     let mut buf = vec![];
     reader.read_to_end(&mut buf).chain_err(|| "Failed to read IRD file")?;
+    read_ird_bytes(buf.as_ref())
+}
+
+/// Parse an IRD file that's already been decompressed, checking its version and CRC32
+pub fn read_ird_bytes(buf: &[u8]) -> Result<IRDFile> {
+    // Check the version by hand first, since nom will just tell us it failed to verify
+    if buf.len() > 4 && &buf[0..4] == b"3IRD" && (buf[4] < IRD_MIN_VERSION || buf[4] > IRD_MAX_VERSION) {
+        bail!(ErrorKind::UnsupportedIRDVersion(buf[4]));
+    }
+
+    let parsed: IRDFile = match parse_ird(buf) {
+        IResult::Done(_, parsed) => parsed,
+        IResult::Error(e) => bail!(parse_error_kind(buf, e)),
+        // Every field is wrapped in complete!(), so this shouldn't happen
+        IResult::Incomplete(_) => bail!(ErrorKind::IRDParseError("unknown", buf.len()))
+    };
 
-    let parsed: IRDFile = parse_ird(buf.as_ref()).unwrap().1;
+    let calculated = ird_crc32(buf);
+    if calculated != parsed.crc32 {
+        bail!(ErrorKind::IRDChecksumMismatch(parsed.crc32, calculated));
+    }
     Ok(parsed)
 }
+
+/// Turn a nom error from `parse_ird` into the field name and byte offset that failed
+fn parse_error_kind(buf: &[u8], err: NomErr<&[u8]>) -> ErrorKind {
+    match err {
+        NomErr::NodePosition(NomErrorKind::Custom(code), position, _) => {
+            let field = IRD_FIELDS.get(code as usize).unwrap_or(&"unknown");
+            ErrorKind::IRDParseError(field, buf.len() - position.len())
+        },
+        NomErr::Position(_, position) => ErrorKind::IRDParseError("unknown", buf.len() - position.len()),
+        _ => ErrorKind::IRDParseError("unknown", 0)
+    }
+}
+
+/// Calculate the CRC32 of a decompressed IRD file
+///
+/// This covers everything except the trailing four bytes, which are the CRC32 itself.
+pub fn ird_crc32(buf: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    if buf.len() > 4 {
+        crc.update(&buf[..buf.len()-4]);
+    }
+    crc.sum()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use disc::tests::noise;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::{env, fs, process};
+    use std::io::Write;
+
+    /// Game ID in `ird_bytes`
+    pub const GAME_ID: &str = "BLUS99999";
+
+    fn le32(n: u32) -> [u8; 4] {
+        [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+    }
+
+    /// A decompressed IRD file of any version, laid out byte by byte
+    ///
+    /// `header` and `footer` go in as is, so gzip them first if they need to decompress.
+    /// The unique identifier is 0x11223344, data1 is all 0xD1, data2 all 0xD2 and the PIC data all 0x50,
+    /// so any field read from the wrong place stands out.
+    pub fn ird_bytes(version: u8, header: &[u8], footer: &[u8]) -> Vec<u8> {
+        let mut ird = b"3IRD".to_vec();
+        ird.push(version);
+        ird.extend(GAME_ID.as_bytes());
+        ird.push(9);
+        ird.extend(b"Test Game");
+        ird.extend(b"0401");
+        ird.extend(b"01.00");
+        ird.extend(b"01.02");
+        if version == 7 {
+            ird.extend(&le32(0x11223344));
+        }
+        ird.extend(&le32(header.len() as u32));
+        ird.extend(header);
+        ird.extend(&le32(footer.len() as u32));
+        ird.extend(footer);
+        // Three regions
+        ird.push(3);
+        for i in 0..3 {
+            ird.extend(&[0xA0 + i; 16]);
+        }
+        // Two files, at sectors 300 and 5000
+        ird.extend(&le32(2));
+        for &(sector, hash) in &[(300u32, 0xF0u8), (5000, 0xF1)] {
+            ird.extend(&le32(sector));
+            ird.extend(&[0, 0, 0, 0]);
+            ird.extend(&[hash; 16]);
+        }
+        ird.extend(&[0, 0, 0, 0]);
+        if version >= 9 {
+            ird.extend(&[0x50; 0x73][..]);
+        }
+        ird.extend(&[0xD1; 16]);
+        ird.extend(&[0xD2; 16]);
+        if version < 9 {
+            ird.extend(&[0x50; 0x73][..]);
+        }
+        if version >= 8 {
+            ird.extend(&le32(0x11223344));
+        }
+        // The CRC32 covers everything but its own four bytes
+        ird.extend(&[0, 0, 0, 0]);
+        let crc = ird_crc32(&ird[..]);
+        let len = ird.len();
+        ird[(len - 4)..].copy_from_slice(&le32(crc));
+        ird
+    }
+
+    #[test]
+    fn valid_checksum() {
+        let ird = ird_bytes(9, b"HEADER", b"FOOTER");
+        let parsed = read_ird_bytes(&ird).unwrap();
+        let crc = ird_crc32(&ird);
+        assert_eq!(parsed.crc32, crc);
+        assert_eq!(&ird[(ird.len() - 4)..], &le32(crc));
+    }
+
+    #[test]
+    fn flipped_bytes_fail_the_checksum() {
+        let ird = ird_bytes(9, b"HEADER", b"FOOTER");
+        // Somewhere in the game name, the file hashes, and data1
+        for &offset in &[20, 100, ird.len() - 0x73 - 4 - 20] {
+            let mut corrupted = ird.clone();
+            corrupted[offset] ^= 0x01;
+            match *read_ird_bytes(&corrupted).unwrap_err().kind() {
+                ErrorKind::IRDChecksumMismatch(expected, calculated) => {
+                    assert_eq!(expected, ird_crc32(&ird));
+                    assert_eq!(calculated, ird_crc32(&corrupted));
+                },
+                ref e => panic!("expected a checksum mismatch at offset {}, got {}", offset, e)
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_checksum() {
+        let mut ird = ird_bytes(8, b"HEADER", b"FOOTER");
+        let last = ird.len() - 1;
+        ird[last] ^= 0xFF;
+        match *read_ird_bytes(&ird).unwrap_err().kind() {
+            ErrorKind::IRDChecksumMismatch(..) => {},
+            ref e => panic!("expected a checksum mismatch, got {}", e)
+        }
+    }
+
+    fn parse(version: u8) -> IRDFile {
+        let parsed = read_ird_bytes(&ird_bytes(version, b"HEADER", b"FOOTER!")).unwrap();
+        assert_eq!(parsed.version, version);
+        assert_eq!(parsed.game_id, GAME_ID);
+        assert_eq!(parsed.game_name, "Test Game");
+        assert_eq!(parsed.update_ver, "0401");
+        assert_eq!(parsed.game_ver, "01.00");
+        assert_eq!(parsed.app_ver, "01.02");
+        assert_eq!(parsed.header_comp, b"HEADER");
+        assert_eq!(parsed.footer_comp, b"FOOTER!");
+        assert_eq!(parsed.region_hashes, vec![[0xA0; 16], [0xA1; 16], [0xA2; 16]]);
+        assert_eq!(parsed.file_hashes, vec![(300, [0xF0; 16]), (5000, [0xF1; 16])]);
+        assert_eq!(parsed.data1, [0xD1; 16]);
+        assert_eq!(parsed.data2, [0xD2; 16]);
+        assert_eq!(parsed.pic_data, vec![0x50; 0x73]);
+        parsed
+    }
+
+    #[test]
+    fn version_6() {
+        assert_eq!(parse(6).unique_identifier, None);
+    }
+
+    #[test]
+    fn version_7_unique_identifier_after_the_versions() {
+        assert_eq!(parse(7).unique_identifier, Some(0x11223344));
+    }
+
+    #[test]
+    fn version_8_unique_identifier_at_the_end() {
+        assert_eq!(parse(8).unique_identifier, Some(0x11223344));
+    }
+
+    #[test]
+    fn version_9_pic_data_before_the_keys() {
+        assert_eq!(parse(9).unique_identifier, Some(0x11223344));
+        // Parsing it as v8 would put the keys where the PIC data is
+        let mut ird = ird_bytes(9, b"HEADER", b"FOOTER!");
+        ird[4] = 8;
+        let crc = ird_crc32(&ird);
+        let len = ird.len();
+        ird[(len - 4)..].copy_from_slice(&le32(crc));
+        let parsed = read_ird_bytes(&ird).unwrap();
+        assert_eq!(parsed.data1, [0x50; 16]);
+    }
+
+    fn parse_error(ird: &[u8]) -> (&'static str, usize) {
+        match *read_ird_bytes(ird).unwrap_err().kind() {
+            ErrorKind::IRDParseError(field, offset) => (field, offset),
+            ref e => panic!("expected a parse error, got {}", e)
+        }
+    }
+
+    #[test]
+    fn unsupported_versions() {
+        for &version in &[0, 5, 10, 255] {
+            let ird = ird_bytes(version, b"HEADER", b"FOOTER");
+            match *read_ird_bytes(&ird).unwrap_err().kind() {
+                ErrorKind::UnsupportedIRDVersion(v) => assert_eq!(v, version),
+                ref e => panic!("expected an unsupported version, got {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_anywhere() {
+        for &version in &[6, 7, 8, 9] {
+            let ird = ird_bytes(version, b"HEADER", b"FOOTER");
+            for len in 0..ird.len() {
+                parse_error(&ird[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn errors_say_where() {
+        let ird = ird_bytes(9, b"HEADER", b"FOOTER");
+        assert_eq!(parse_error(&ird[..20]), ("game_name", 15));
+        let mut bad_magic = ird.clone();
+        bad_magic[0] = b'4';
+        assert_eq!(parse_error(&bad_magic), ("magic", 0));
+    }
+
+    #[test]
+    fn huge_lengths_and_counts() {
+        let ird = ird_bytes(9, b"HEADER", b"FOOTER");
+        // Header length, right after the versions
+        let mut huge_header = ird.clone();
+        huge_header[38..42].copy_from_slice(&le32(0xFFFF_FFFF));
+        assert_eq!(parse_error(&huge_header).0, "header");
+        // File hash count, after the header, footer and region hashes
+        let file_count = 42 + 6 + 4 + 6 + 1 + 3 * 16;
+        for &count in &[0x7FFF_FFFF, 0xFFFF_FFFF] {
+            let mut huge_files = ird.clone();
+            huge_files[file_count..(file_count + 4)].copy_from_slice(&le32(count));
+            assert_eq!(parse_error(&huge_files).0, "file_hashes");
+        }
+    }
+
+    pub fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn header_and_footer_round_trip() {
+        let header = noise(3 * 2048, 1);
+        let footer = noise(2048, 2);
+        let parsed = read_ird_bytes(&ird_bytes(9, &gzip(&header), &gzip(&footer))).unwrap();
+        assert!(parsed.header().unwrap() == header);
+        assert!(parsed.footer().unwrap() == footer);
+        assert_eq!(parsed.header_sectors().unwrap(), 3);
+        assert_eq!(parsed.footer_sectors().unwrap(), 1);
+    }
+
+    #[test]
+    fn read_from_a_gzipped_file() {
+        let header = noise(2 * 2048, 3);
+        let path = env::temp_dir().join(format!("ps3bdtool-test-{}-read.ird", process::id()));
+        File::create(&path).unwrap().write_all(&gzip(&ird_bytes(8, &gzip(&header), &gzip(b"")))).unwrap();
+        let parsed = read_ird(&path);
+        fs::remove_file(&path).ok();
+        let parsed = parsed.unwrap();
+        assert!(parsed.header().unwrap() == header);
+        assert_eq!(parsed.footer_sectors().unwrap(), 0);
+    }
+
+    #[test]
+    fn corrupt_gzip() {
+        let mut header = gzip(&noise(3 * 2048, 1));
+        let middle = header.len() / 2;
+        header[middle] ^= 0xFF;
+        let parsed = read_ird_bytes(&ird_bytes(9, &header, b"not gzip at all")).unwrap();
+        assert!(parsed.header().is_err());
+        assert!(parsed.header_sectors().is_err());
+        assert!(parsed.footer().is_err());
+        assert!(parsed.footer_sectors().is_err());
+    }
+
+    #[test]
+    fn partial_sectors() {
+        let parsed = read_ird_bytes(&ird_bytes(9, &gzip(&[0; 3000]), &gzip(&[0; 2048]))).unwrap();
+        assert_eq!(parsed.header().unwrap().len(), 3000);
+        assert!(parsed.header_sectors().is_err());
+    }
+}
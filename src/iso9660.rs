@@ -0,0 +1,701 @@
+use super::errors::*;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::cmp;
+use std::collections::HashSet;
+use disc::PS3Disc;
+
+/// Sector the primary volume descriptor is in
+pub const PVD_SECTOR: u32 = 16;
+
+/// File flag: this is a directory
+const FLAG_DIRECTORY: u8 = 0x02;
+/// File flag: this isn't the final directory record for the file,
+/// which is how files bigger than 4GiB get split into multiple extents
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+/// given a four-element &[u8], calculate the little-endian u32 that they represent
+///
+/// ISO9660 stores most numbers twice, little-endian then big-endian, so we just read the first.
+fn le_u32(i: &[u8]) -> u32 {
+    debug_assert_eq!(i.len(), 4, "a u32 is 4 bytes and yet I didn't get 4 bytes");
+    (i[0] as u32) + ((i[1] as u32) << 8) + ((i[2] as u32) << 16) + ((i[3] as u32) << 24)
+}
+
+/// given a two-element &[u8], calculate the little-endian u16 that they represent
+fn le_u16(i: &[u8]) -> u16 {
+    debug_assert_eq!(i.len(), 2, "a u16 is 2 bytes and yet I didn't get 2 bytes");
+    (i[0] as u16) + ((i[1] as u16) << 8)
+}
+
+/// Turn a space-padded ISO9660 string field into a String
+fn padded_str(i: &[u8]) -> String {
+    String::from_utf8_lossy(i).trim_right().to_string()
+}
+
+/// A contiguous run of sectors holding (part of) a file's data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// First sector of the data
+    pub start: u32,
+    /// Length of the data in bytes
+    pub length: u64
+}
+
+/// A file or directory on the disc, from its ISO9660 directory record(s)
+#[derive(Debug, Clone)]
+pub struct DirectoryRecord {
+    /// Name, with the ";1" version suffix removed
+    pub name: String,
+    /// Where the data is. Only files over 4GiB have more than one of these.
+    pub extents: Vec<Extent>,
+    /// Total length of the data in bytes
+    pub size: u64,
+    /// Raw ISO9660 file flags
+    pub flags: u8,
+    /// Raw ISO9660 recording date and time
+    pub recorded: [u8; 7],
+    /// Byte offset of the directory record itself on the disc
+    ///
+    /// This is unique for every file and directory, so it makes a handy ID.
+    pub position: u64
+}
+
+impl DirectoryRecord {
+    /// Parse a single directory record from the start of `buf`
+    ///
+    /// Returns the record and its length in bytes, or None if there isn't one here
+    /// (a zero length byte means the rest of the sector is padding).
+    pub fn parse(buf: &[u8]) -> Result<Option<(DirectoryRecord, usize)>> {
+        if buf.is_empty() || buf[0] == 0 {
+            return Ok(None);
+        }
+        let len = buf[0] as usize;
+        if len < 34 || len > buf.len() {
+            bail!("Directory record with invalid length {}", len);
+        }
+        let name_len = buf[32] as usize;
+        if 33 + name_len > len {
+            bail!("Directory record name runs past the end of the record");
+        }
+        let raw_name = &buf[33..(33+name_len)];
+        let name = if raw_name == [0] {
+            ".".to_string()
+        } else if raw_name == [1] {
+            "..".to_string()
+        } else {
+            let name = String::from_utf8_lossy(raw_name);
+            // Files have a ";1" version suffix, and sometimes a trailing dot with no extension
+            let name = name.split(';').next().unwrap_or("");
+            name.trim_right_matches('.').to_string()
+        };
+        let mut recorded = [0u8; 7];
+        recorded.copy_from_slice(&buf[18..25]);
+        let extent = Extent {
+            start: le_u32(&buf[2..6]),
+            length: le_u32(&buf[10..14]) as u64
+        };
+        Ok(Some((DirectoryRecord {
+            name, recorded,
+            extents: vec![extent],
+            size: extent.length,
+            flags: buf[25],
+            position: 0
+        }, len)))
+    }
+
+    /// Whether this is a directory or not
+    pub fn is_dir(&self) -> bool {
+        self.flags & FLAG_DIRECTORY != 0
+    }
+
+    /// Recording date and time as seconds since the Unix epoch
+    pub fn recorded_timestamp(&self) -> i64 {
+        let r = &self.recorded;
+        let (year, month, day) = (1900 + r[0] as i64, r[1] as i64, r[2] as i64);
+        // Days since the epoch, from Howard Hinnant's days_from_civil
+        let y = if month <= 2 { year - 1 } else { year };
+        let era = (if y >= 0 { y } else { y - 399 }) / 400;
+        let yoe = y - era * 400;
+        let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        // The last byte is the offset from GMT, in 15 minute intervals
+        let gmt_offset = (r[6] as i8) as i64 * 15 * 60;
+        days * 86400 + r[3] as i64 * 3600 + r[4] as i64 * 60 + r[5] as i64 - gmt_offset
+    }
+
+    /// First sector of the data, which is what IRD file hashes are keyed by
+    pub fn start_sector(&self) -> u32 {
+        self.extents.first().map_or(0, |e| e.start)
+    }
+}
+
+/// The ISO9660 primary volume descriptor, which describes the whole filesystem
+#[derive(Debug, Clone)]
+pub struct PrimaryVolumeDescriptor {
+    /// System identifier, ex "PS3VOLUME"
+    pub system_id: String,
+    /// Volume identifier, ex "PS3VOLUME"
+    pub volume_id: String,
+    /// Size of the volume in logical blocks
+    pub volume_space_size: u32,
+    /// Size of a logical block in bytes, always 2048 on a PS3 disc
+    pub logical_block_size: u16,
+    /// Size of the path table in bytes
+    pub path_table_size: u32,
+    /// Sector of the little-endian path table
+    pub path_table_location: u32,
+    /// The root directory
+    pub root: DirectoryRecord
+}
+
+impl PrimaryVolumeDescriptor {
+    /// Read the primary volume descriptor from the disc
+    pub fn read<F: Read+Seek>(disc: &mut PS3Disc<F>) -> Result<Self> {
+        let pvd = disc.read_sector(PVD_SECTOR).chain_err(|| "Failed to read primary volume descriptor")?;
+        if pvd[0] != 1 || &pvd[1..6] != b"CD001" {
+            bail!("No ISO9660 primary volume descriptor found");
+        }
+        let logical_block_size = le_u16(&pvd[128..130]);
+        if logical_block_size != 2048 {
+            bail!("Unsupported ISO9660 logical block size {}", logical_block_size);
+        }
+        let mut root = match DirectoryRecord::parse(&pvd[156..190]).chain_err(|| "Bad root directory record")? {
+            Some((record, _)) => record,
+            None => bail!("Primary volume descriptor is missing the root directory record")
+        };
+        root.position = PVD_SECTOR as u64 * 2048 + 156;
+        Ok(PrimaryVolumeDescriptor {
+            logical_block_size, root,
+            system_id: padded_str(&pvd[8..40]),
+            volume_id: padded_str(&pvd[40..72]),
+            volume_space_size: le_u32(&pvd[80..84]),
+            path_table_size: le_u32(&pvd[132..136]),
+            path_table_location: le_u32(&pvd[140..144])
+        })
+    }
+}
+
+/// An entry in the path table, which lists every directory on the disc
+#[derive(Debug, Clone)]
+pub struct PathTableEntry {
+    /// Name of the directory, empty for the root
+    pub name: String,
+    /// First sector of the directory's records
+    pub extent: u32,
+    /// Number of the parent directory's entry, starting from 1 (the root, which is its own parent)
+    pub parent: u16
+}
+
+/// Read the path table, which lists every directory on the disc in one place
+pub fn read_path_table<F: Read+Seek>(disc: &mut PS3Disc<F>, pvd: &PrimaryVolumeDescriptor) -> Result<Vec<PathTableEntry>> {
+    let sectors = (pvd.path_table_size as u64 + 2047) / 2048;
+    if pvd.path_table_location as u64 + sectors > disc.total_sectors as u64 {
+        bail!("Path table runs past the end of the disc");
+    }
+    let sectors = sectors as u32;
+    let mut buf = Vec::with_capacity(sectors as usize * 2048);
+    for sector in pvd.path_table_location..(pvd.path_table_location+sectors) {
+        buf.extend(disc.read_sector(sector).chain_err(|| format!("Failed to read path table sector {}", sector))?);
+    }
+    buf.truncate(pvd.path_table_size as usize);
+
+    let mut entries = vec![];
+    let mut pos = 0;
+    while pos + 8 <= buf.len() {
+        let name_len = buf[pos] as usize;
+        if name_len == 0 || pos + 8 + name_len > buf.len() {
+            bail!("Bad path table entry at offset {}", pos);
+        }
+        let name = &buf[(pos+8)..(pos+8+name_len)];
+        entries.push(PathTableEntry {
+            name: if name == [0] { String::new() } else { String::from_utf8_lossy(name).to_string() },
+            extent: le_u32(&buf[(pos+2)..(pos+6)]),
+            parent: le_u16(&buf[(pos+6)..(pos+8)])
+        });
+        // Entries are padded to an even length
+        pos += 8 + name_len + (name_len % 2);
+    }
+    Ok(entries)
+}
+
+/// Iterator over the entries in a directory, not including "." and ".."
+///
+/// The directory's sectors are read up front by `read_dir`, so this doesn't hold on to the disc.
+pub struct ReadDir {
+    /// Every sector of the directory, back to back
+    buf: Vec<u8>,
+    /// Sector each 2048 bytes of `buf` came from
+    sectors: Vec<u32>,
+    /// Current position in `buf`
+    pos: usize
+}
+
+impl ReadDir {
+    /// Parse the next record, skipping over the zero padding at the end of sectors
+    fn next_record(&mut self) -> Result<Option<DirectoryRecord>> {
+        while self.pos < self.buf.len() {
+            let sector_end = (self.pos / 2048 + 1) * 2048;
+            // Directory records never cross a sector boundary
+            match DirectoryRecord::parse(&self.buf[self.pos..sector_end])
+                .chain_err(|| format!("Bad directory record in sector {} at offset {}", self.sectors[self.pos / 2048], self.pos % 2048))? {
+                Some((mut record, len)) => {
+                    record.position = self.sectors[self.pos / 2048] as u64 * 2048 + (self.pos % 2048) as u64;
+                    self.pos += len;
+                    return Ok(Some(record));
+                },
+                None => self.pos = sector_end
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for ReadDir {
+    type Item = Result<DirectoryRecord>;
+
+    fn next(&mut self) -> Option<Result<DirectoryRecord>> {
+        loop {
+            let mut record = match self.next_record() {
+                Ok(Some(record)) => record,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e))
+            };
+            // Files over 4GiB are split over several records, one after the other
+            let mut continued = record.flags & FLAG_MULTI_EXTENT != 0;
+            while continued {
+                match self.next_record() {
+                    Ok(Some(next)) => {
+                        continued = next.flags & FLAG_MULTI_EXTENT != 0;
+                        record.extents.extend(next.extents);
+                        record.size += next.size;
+                    },
+                    Ok(None) => continued = false,
+                    Err(e) => return Some(Err(e))
+                }
+            }
+            if record.name != "." && record.name != ".." {
+                return Some(Ok(record));
+            }
+        }
+    }
+}
+
+/// Start reading a directory
+pub fn read_dir<F: Read+Seek>(disc: &mut PS3Disc<F>, dir: &DirectoryRecord) -> Result<ReadDir> {
+    if !dir.is_dir() {
+        bail!("{} isn't a directory", dir.name);
+    }
+    let mut buf = vec![];
+    let mut sectors = vec![];
+    for extent in &dir.extents {
+        let count = (extent.length + 2047) / 2048;
+        // Don't trust the disc with how much to read
+        if extent.start as u64 + count > disc.total_sectors as u64 {
+            bail!("Directory {} runs past the end of the disc", dir.name);
+        }
+        let count = count as u32;
+        for sector in extent.start..(extent.start+count) {
+            buf.extend(disc.read_sector(sector).chain_err(|| format!("Failed to read directory sector {}", sector))?);
+            sectors.push(sector);
+        }
+    }
+    Ok(ReadDir {
+        buf, sectors,
+        pos: 0
+    })
+}
+
+/// Get the root directory from the primary volume descriptor
+pub fn root_directory<F: Read+Seek>(disc: &mut PS3Disc<F>) -> Result<DirectoryRecord> {
+    Ok(PrimaryVolumeDescriptor::read(disc)?.root)
+}
+
+/// Read the contents of a directory, not including the "." and ".." entries
+pub fn read_directory<F: Read+Seek>(disc: &mut PS3Disc<F>, dir: &DirectoryRecord) -> Result<Vec<DirectoryRecord>> {
+    read_dir(disc, dir)?.collect()
+}
+
+/// Find a file or directory by its path, ex `PS3_GAME/PARAM.SFO`
+///
+/// Names are matched case-insensitively. An empty path gives the root directory.
+pub fn lookup<F: Read+Seek>(disc: &mut PS3Disc<F>, path: &str) -> Result<Option<DirectoryRecord>> {
+    let mut current = root_directory(disc)?;
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        if !current.is_dir() {
+            return Ok(None);
+        }
+        let mut found = None;
+        for entry in read_dir(disc, &current)? {
+            let entry = entry?;
+            if entry.name.eq_ignore_ascii_case(component) {
+                found = Some(entry);
+                break;
+            }
+        }
+        match found {
+            Some(entry) => current = entry,
+            None => return Ok(None)
+        }
+    }
+    Ok(Some(current))
+}
+
+/// Recursively list every file on the disc, along with its path
+///
+/// Paths are relative to the root and use forward slashes, ex `PS3_GAME/PARAM.SFO`.
+/// Directories aren't included, only the files in them.
+pub fn walk<F: Read+Seek>(disc: &mut PS3Disc<F>) -> Result<Vec<(String, DirectoryRecord)>> {
+    Ok(walk_all(disc)?.into_iter().filter(|f| !f.1.is_dir()).collect())
+}
+
+/// Recursively list every file and directory on the disc, along with its path
+///
+/// Same as `walk`, but with directories included. Sorted by path, so each directory
+/// comes before everything in it. The root directory itself isn't included.
+///
+/// It's an error for a directory to show up twice, which would otherwise loop forever.
+pub fn walk_all<F: Read+Seek>(disc: &mut PS3Disc<F>) -> Result<Vec<(String, DirectoryRecord)>> {
+    let root = root_directory(disc)?;
+    let mut visited = HashSet::new();
+    visited.insert(root.start_sector());
+    let mut entries = vec![];
+    let mut stack = vec![(String::new(), root)];
+    while let Some((path, dir)) = stack.pop() {
+        for entry in read_dir(disc, &dir)? {
+            let entry = entry?;
+            let entry_path = if path.is_empty() {
+                entry.name.clone()
+            } else {
+                format!("{}/{}", path, entry.name)
+            };
+            if entry.is_dir() {
+                if !visited.insert(entry.start_sector()) {
+                    bail!("Directory {} loops back to one that's already been read", entry_path);
+                }
+                stack.push((entry_path.clone(), entry.clone()));
+            }
+            entries.push((entry_path, entry));
+        }
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(entries)
+}
+
+/// Open a file on the disc for reading
+pub fn open<'a, F: Read+Seek>(disc: &'a mut PS3Disc<F>, file: &DirectoryRecord) -> Result<IsoFile<'a, F>> {
+    if file.is_dir() {
+        bail!("{} is a directory", file.name);
+    }
+    Ok(IsoFile {
+        disc,
+        extents: file.extents.clone(),
+        size: file.size,
+        pos: 0
+    })
+}
+
+/// A file on the disc, transparently decrypted as it's read
+pub struct IsoFile<'a, F: 'a> {
+    disc: &'a mut PS3Disc<F>,
+    extents: Vec<Extent>,
+    size: u64,
+    pos: u64
+}
+
+impl<'a, F: Read+Seek> IsoFile<'a, F> {
+    /// Length of the file in bytes
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether the file is empty or not
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Find the sector holding a byte of the file, and where in that sector the byte is
+    fn locate(&self, pos: u64) -> Option<(u32, usize)> {
+        let mut extent_start = 0u64;
+        for extent in &self.extents {
+            if pos < extent_start + extent.length {
+                let offset = pos - extent_start;
+                return Some((extent.start + (offset / 2048) as u32, (offset % 2048) as usize));
+            }
+            extent_start += extent.length;
+        }
+        None
+    }
+}
+
+impl<'a, F: Read+Seek> Read for IsoFile<'a, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (sector, offset) = match self.locate(self.pos) {
+            Some(location) => location,
+            None => return Ok(0)
+        };
+        let data = self.disc.read_sector(sector)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let len = cmp::min(cmp::min(buf.len(), 2048 - offset) as u64, self.size - self.pos) as usize;
+        buf[..len].copy_from_slice(&data[offset..(offset+len)]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<'a, F: Read+Seek> Seek for IsoFile<'a, F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => self.size as i64 + n,
+            SeekFrom::Current(n) => self.pos as i64 + n
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use disc::tests::{noise, test_disc, test_image, write_sectors};
+    use std::io::Cursor;
+
+    /// Where the root directory's records are in `test_iso`
+    pub const ROOT_SECTOR: u32 = 18;
+    /// Where the PS3_GAME directory's records are in `test_iso`
+    pub const GAME_SECTOR: u32 = 19;
+    /// Where PS3_DISC.SFB is in `test_iso`
+    pub const SFB_SECTOR: u32 = 20;
+    /// Where PARAM.SFO is in `test_iso`
+    pub const SFO_SECTOR: u32 = 24;
+
+    /// A single ISO9660 directory record
+    pub fn record(name: &[u8], start: u32, length: u32, flags: u8) -> Vec<u8> {
+        let len = 33 + name.len() + (1 - name.len() % 2);
+        let mut record = vec![0u8; len];
+        record[0] = len as u8;
+        record[2..6].copy_from_slice(&le_bytes(start));
+        record[6..10].copy_from_slice(&be_bytes(start));
+        record[10..14].copy_from_slice(&le_bytes(length));
+        record[14..18].copy_from_slice(&be_bytes(length));
+        record[18..25].copy_from_slice(&[118, 1, 2, 3, 4, 5, 0]);
+        record[25] = flags;
+        record[28] = 1;
+        record[32] = name.len() as u8;
+        record[33..(33 + name.len())].copy_from_slice(name);
+        record
+    }
+
+    fn le_bytes(n: u32) -> [u8; 4] {
+        [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+    }
+
+    fn be_bytes(n: u32) -> [u8; 4] {
+        [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+    }
+
+    /// A directory's sector, "." and ".." followed by `records`
+    fn directory(sector: u32, parent: u32, records: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = record(&[0], sector, 2048, FLAG_DIRECTORY);
+        data.extend(record(&[1], parent, 2048, FLAG_DIRECTORY));
+        for record in records {
+            data.extend(record);
+        }
+        data
+    }
+
+    /// Contents of PS3_DISC.SFB in `test_iso`
+    pub fn sfb_data() -> Vec<u8> {
+        noise(512, 21)
+    }
+
+    /// Contents of PARAM.SFO in `test_iso`
+    pub fn sfo_data() -> Vec<u8> {
+        noise(500, 24)
+    }
+
+    /// Contents of BIG.DAT in `test_iso`, which is split over two extents
+    /// in sectors 21-22 (encrypted) and 30-31 (not)
+    pub fn big_data() -> Vec<u8> {
+        noise(4096 + 3000, 30)
+    }
+
+    /// `test_image` with a filesystem on it:
+    ///
+    /// ```text
+    /// BIG.DAT
+    /// PS3_DISC.SFB
+    /// PS3_GAME/
+    /// PS3_GAME/PARAM.SFO
+    /// ```
+    pub fn test_iso() -> Vec<u8> {
+        test_iso_with(&[], &[])
+    }
+
+    /// `test_iso`, with extra records in the root and PS3_GAME directories
+    pub fn test_iso_with(root: &[Vec<u8>], game: &[Vec<u8>]) -> Vec<u8> {
+        let mut image = test_image();
+
+        let mut pvd = vec![0u8; 2048];
+        pvd[0] = 1;
+        pvd[1..6].copy_from_slice(b"CD001");
+        pvd[6] = 1;
+        pvd[8..40].copy_from_slice(b"PS3VOLUME                       ");
+        pvd[40..72].copy_from_slice(b"PS3VOLUME                       ");
+        pvd[80..84].copy_from_slice(&le_bytes(40));
+        pvd[128..130].copy_from_slice(&[0x00, 0x08]);
+        pvd[132..136].copy_from_slice(&le_bytes(26));
+        pvd[140..144].copy_from_slice(&le_bytes(17));
+        pvd[156..190].copy_from_slice(&record(&[0], ROOT_SECTOR, 2048, FLAG_DIRECTORY));
+        write_sectors(&mut image, PVD_SECTOR, &pvd);
+
+        let mut path_table = vec![1, 0];
+        path_table.extend(&le_bytes(ROOT_SECTOR));
+        path_table.extend(&[1, 0, 0, 0]);
+        path_table.extend(&[8, 0]);
+        path_table.extend(&le_bytes(GAME_SECTOR));
+        path_table.extend(&[1, 0]);
+        path_table.extend(b"PS3_GAME");
+        write_sectors(&mut image, 17, &path_table);
+
+        let mut root_records = vec![
+            record(b"BIG.DAT;1", 21, 4096, FLAG_MULTI_EXTENT),
+            record(b"BIG.DAT;1", 30, 3000, 0),
+            record(b"PS3_DISC.SFB;1", SFB_SECTOR, 512, 0),
+            record(b"PS3_GAME", GAME_SECTOR, 2048, FLAG_DIRECTORY)
+        ];
+        root_records.extend(root.iter().cloned());
+        write_sectors(&mut image, ROOT_SECTOR, &directory(ROOT_SECTOR, ROOT_SECTOR, &root_records));
+        let mut game_records = vec![record(b"PARAM.SFO;1", SFO_SECTOR, 500, 0)];
+        game_records.extend(game.iter().cloned());
+        write_sectors(&mut image, GAME_SECTOR, &directory(GAME_SECTOR, ROOT_SECTOR, &game_records));
+
+        write_sectors(&mut image, SFB_SECTOR, &sfb_data());
+        write_sectors(&mut image, SFO_SECTOR, &sfo_data());
+        let big = big_data();
+        write_sectors(&mut image, 21, &big[..4096]);
+        write_sectors(&mut image, 30, &big[4096..]);
+        image
+    }
+
+    fn read_file(disc: &mut PS3Disc<Cursor<Vec<u8>>>, path: &str) -> Vec<u8> {
+        let record = lookup(disc, path).unwrap().unwrap();
+        let mut data = vec![];
+        open(disc, &record).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn reads_the_volume_descriptor_and_path_table() {
+        let mut disc = test_disc(test_iso());
+        let pvd = PrimaryVolumeDescriptor::read(&mut disc).unwrap();
+        assert_eq!(pvd.volume_id, "PS3VOLUME");
+        assert_eq!(pvd.volume_space_size, 40);
+        assert_eq!(pvd.root.start_sector(), ROOT_SECTOR);
+        assert!(pvd.root.is_dir());
+        let path_table = read_path_table(&mut disc, &pvd).unwrap();
+        assert_eq!(path_table.iter().map(|e| (e.name.as_str(), e.extent, e.parent)).collect::<Vec<_>>(),
+                   vec![("", ROOT_SECTOR, 1), ("PS3_GAME", GAME_SECTOR, 1)]);
+    }
+
+    #[test]
+    fn walks_every_file() {
+        let mut disc = test_disc(test_iso());
+        let all = walk_all(&mut disc).unwrap();
+        assert_eq!(all.iter().map(|f| f.0.as_str()).collect::<Vec<_>>(),
+                   vec!["BIG.DAT", "PS3_DISC.SFB", "PS3_GAME", "PS3_GAME/PARAM.SFO"]);
+        let files = walk(&mut disc).unwrap();
+        assert_eq!(files.iter().map(|f| f.0.as_str()).collect::<Vec<_>>(),
+                   vec!["BIG.DAT", "PS3_DISC.SFB", "PS3_GAME/PARAM.SFO"]);
+        // 2018-01-02 03:04:05 GMT
+        assert_eq!(files[1].1.recorded_timestamp(), 1514862245);
+    }
+
+    #[test]
+    fn multi_extent_files() {
+        let mut disc = test_disc(test_iso());
+        let big = lookup(&mut disc, "BIG.DAT").unwrap().unwrap();
+        assert_eq!(big.extents, vec![Extent { start: 21, length: 4096 }, Extent { start: 30, length: 3000 }]);
+        assert_eq!(big.size, 7096);
+        assert_eq!(read_file(&mut disc, "BIG.DAT"), big_data());
+        assert_eq!(read_file(&mut disc, "PS3_GAME/PARAM.SFO"), sfo_data());
+    }
+
+    #[test]
+    fn seeking_around_extent_boundaries() {
+        let mut disc = test_disc(test_iso());
+        let big = lookup(&mut disc, "BIG.DAT").unwrap().unwrap();
+        let data = big_data();
+        let mut file = open(&mut disc, &big).unwrap();
+        assert_eq!(file.len(), 7096);
+
+        // Reads stop at the end of each sector, and so at the end of each extent
+        let mut buf = [0u8; 10];
+        file.seek(SeekFrom::Start(4096 - 5)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], &data[(4096 - 5)..4096]);
+        assert_eq!(file.read(&mut buf).unwrap(), 10);
+        assert_eq!(&buf[..], &data[4096..4106]);
+
+        file.seek(SeekFrom::Current(-20)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[(4106 - 20)..(4106 - 10)]);
+
+        file.seek(SeekFrom::End(-3)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], &data[(7096 - 3)..]);
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+        assert!(file.seek(SeekFrom::End(-7097)).is_err());
+    }
+
+    #[test]
+    fn lookups() {
+        let mut disc = test_disc(test_iso());
+        assert_eq!(lookup(&mut disc, "ps3_game/param.sfo").unwrap().unwrap().start_sector(), SFO_SECTOR);
+        assert_eq!(lookup(&mut disc, "").unwrap().unwrap().start_sector(), ROOT_SECTOR);
+        assert!(lookup(&mut disc, "PS3_GAME/NOPE.SFO").unwrap().is_none());
+        assert!(lookup(&mut disc, "NOPE/PARAM.SFO").unwrap().is_none());
+        // Files don't have anything in them
+        assert!(lookup(&mut disc, "PS3_DISC.SFB/PARAM.SFO").unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_records() {
+        let mut short = record(b"SHORT.BIN;1", 25, 10, 0);
+        short[0] = 20;
+        let mut disc = test_disc(test_iso_with(&[short], &[]));
+        assert!(walk_all(&mut disc).is_err());
+
+        let mut long_name = record(b"NAME.BIN;1", 25, 10, 0);
+        long_name[32] = 200;
+        let mut disc = test_disc(test_iso_with(&[], &[long_name]));
+        assert!(walk_all(&mut disc).is_err());
+        // The root's still fine
+        assert!(lookup(&mut disc, "PS3_DISC.SFB").unwrap().is_some());
+    }
+
+    #[test]
+    fn directory_loops_are_errors() {
+        let back_to_root = record(b"LOOP", ROOT_SECTOR, 2048, FLAG_DIRECTORY);
+        let mut disc = test_disc(test_iso_with(&[], &[back_to_root]));
+        assert!(walk_all(&mut disc).is_err());
+    }
+
+    #[test]
+    fn huge_directories_are_errors() {
+        let huge = record(b"HUGE", 25, 0xFFFF_FFFF, FLAG_DIRECTORY);
+        let mut disc = test_disc(test_iso_with(&[huge], &[]));
+        let dir = lookup(&mut disc, "HUGE").unwrap().unwrap();
+        assert!(read_dir(&mut disc, &dir).is_err());
+        assert!(walk_all(&mut disc).is_err());
+
+        let mut disc = test_disc(test_iso());
+        let mut pvd = PrimaryVolumeDescriptor::read(&mut disc).unwrap();
+        pvd.path_table_size = 0xFFFF_FFFF;
+        assert!(read_path_table(&mut disc, &pvd).is_err());
+    }
+}
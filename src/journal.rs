@@ -0,0 +1,242 @@
+use super::errors::*;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write, ErrorKind as IoErrorKind};
+use std::path::{Path, PathBuf};
+use disc::PS3Disc;
+
+/// How many sectors to decrypt between journal updates, 32MiB worth
+pub const JOURNAL_INTERVAL: u32 = 16384;
+
+/// Sidecar file recording how far a decrypt got, so it can be resumed
+///
+/// This only records how many sectors from the start of the disc are known to be written,
+/// anything past that gets decrypted again on resume.
+///
+/// It's a small text file next to the output, ex `game.dec.iso.journal`,
+/// and it's removed once the decrypt finishes.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    /// Where the journal file lives
+    pub path: PathBuf,
+    /// Game ID of the disc being decrypted, ex BCUS-12345
+    pub gameid: String,
+    /// Total number of sectors on the disc being decrypted
+    pub total_sectors: u32,
+    /// Number of sectors, starting from 0, that have been written to the output
+    pub completed: u32
+}
+
+impl Journal {
+    /// Where the journal for an output file goes
+    pub fn path_for<P: AsRef<Path>>(output: P) -> PathBuf {
+        let mut path = output.as_ref().as_os_str().to_owned();
+        path.push(".journal");
+        PathBuf::from(path)
+    }
+
+    /// Start a new journal for decrypting a disc to an output file
+    ///
+    /// Nothing is written until `record` is called.
+    pub fn new<F: Read+Seek, P: AsRef<Path>>(disc: &PS3Disc<F>, output: P) -> Self {
+        Journal {
+            path: Journal::path_for(output),
+            gameid: disc.gameid.clone(),
+            total_sectors: disc.total_sectors,
+            completed: 0
+        }
+    }
+
+    /// Load the journal for an output file, if there is one
+    ///
+    /// It's an error if the journal is for a different disc.
+    pub fn load<F: Read+Seek, P: AsRef<Path>>(disc: &PS3Disc<F>, output: P) -> Result<Option<Self>> {
+        let mut journal = Journal::new(disc, output);
+        let mut text = String::new();
+        match File::open(&journal.path) {
+            Ok(mut f) => f.read_to_string(&mut text).chain_err(|| "Failed to read journal")?,
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).chain_err(|| "Failed to open journal")
+        };
+
+        let mut gameid = None;
+        let mut total_sectors = None;
+        let mut completed = None;
+        for line in text.lines() {
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next().unwrap().trim();
+            let value = parts.next().unwrap_or("").trim();
+            match name {
+                "gameid" => gameid = Some(value.to_string()),
+                "total_sectors" => total_sectors = value.parse::<u32>().ok(),
+                "completed" => completed = value.parse::<u32>().ok(),
+                _ => {}
+            }
+        }
+        match (gameid, total_sectors, completed) {
+            (Some(gameid), Some(total_sectors), Some(completed)) => {
+                if gameid != journal.gameid || total_sectors != journal.total_sectors {
+                    bail!("Journal {} is for a different disc ({}, {} sectors)", journal.path.display(), gameid, total_sectors);
+                }
+                if completed > total_sectors {
+                    bail!("Journal {} is corrupted, it has more sectors completed than the disc has", journal.path.display());
+                }
+                journal.completed = completed;
+                Ok(Some(journal))
+            },
+            _ => bail!("Journal {} is corrupted", journal.path.display())
+        }
+    }
+
+    /// Record that the first `completed` sectors have been written
+    ///
+    /// Make sure they've actually been flushed to the output first.
+    pub fn record(&mut self, completed: u32) -> Result<()> {
+        self.completed = completed;
+        let text = format!("gameid: {}\ntotal_sectors: {}\ncompleted: {}\n", self.gameid, self.total_sectors, self.completed);
+        // Write to a temporary file and move it into place, so the journal is never half-written
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        File::create(&tmp_path).chain_err(|| "Failed to create journal")?
+            .write_all(text.as_bytes()).chain_err(|| "Failed to write journal")?;
+        fs::rename(&tmp_path, &self.path).chain_err(|| "Failed to write journal")
+    }
+
+    /// Check that the output is long enough to hold every sector the journal says was written,
+    /// and that the last of them actually matches the disc
+    ///
+    /// This catches resuming with the wrong output file, or an output file that's been messed with.
+    pub fn validate<F: Read+Seek, O: Read+Seek>(&self, disc: &mut PS3Disc<F>, output: &mut O) -> Result<bool> {
+        if self.completed == 0 {
+            return Ok(true);
+        }
+        let length = output.seek(SeekFrom::End(0)).chain_err(|| "Failed to seek output")?;
+        if length < self.completed as u64 * 2048 {
+            return Ok(false);
+        }
+        let sector = self.completed - 1;
+        let expected = disc.read_sector(sector).chain_err(|| format!("Failed to read sector {}", sector))?;
+        let mut actual = [0u8; 2048];
+        output.seek(SeekFrom::Start(sector as u64 * 2048)).chain_err(|| "Failed to seek output")?;
+        match output.read_exact(&mut actual) {
+            Ok(_) => Ok(expected.as_slice() == &actual[..]),
+            Err(ref e) if e.kind() == IoErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e).chain_err(|| "Failed to read output")
+        }
+    }
+
+    /// Remove the journal, once the decrypt is done
+    pub fn finish(self) -> Result<()> {
+        self.remove()
+    }
+
+    /// Remove the journal file, if there is one
+    pub fn remove(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).chain_err(|| "Failed to remove journal")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+    use std::io::Cursor;
+    use disc::tests::{test_disc, test_image};
+
+    fn output_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("ps3bdtool-test-{}-{}.dec.iso", process::id(), name))
+    }
+
+    fn write_journal(path: &Path, text: &str) {
+        File::create(path).unwrap().write_all(text.as_bytes()).unwrap();
+    }
+
+    /// The first `sectors` sectors of the test disc, decrypted
+    fn decrypted(sectors: u32) -> Vec<u8> {
+        let mut disc = test_disc(test_image());
+        let mut data = vec![0u8; sectors as usize * 2048];
+        disc.read_sectors_into(0, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn path_for() {
+        assert_eq!(Journal::path_for("/tmp/game.dec.iso"), PathBuf::from("/tmp/game.dec.iso.journal"));
+    }
+
+    #[test]
+    fn record_and_load() {
+        let disc = test_disc(test_image());
+        let output = output_path("journal-record");
+        assert!(Journal::load(&disc, &output).unwrap().is_none());
+
+        let mut journal = Journal::new(&disc, &output);
+        assert_eq!(journal.completed, 0);
+        // Nothing's written until there's something to record
+        assert!(!journal.path.exists());
+        journal.record(20).unwrap();
+        journal.record(30).unwrap();
+        let mut tmp_path = journal.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        assert!(!Path::new(&tmp_path).exists());
+
+        let loaded = Journal::load(&disc, &output).unwrap().unwrap();
+        assert_eq!(loaded.path, journal.path);
+        assert_eq!(loaded.gameid, disc.gameid);
+        assert_eq!(loaded.total_sectors, 40);
+        assert_eq!(loaded.completed, 30);
+
+        loaded.finish().unwrap();
+        assert!(!journal.path.exists());
+        assert!(Journal::load(&disc, &output).unwrap().is_none());
+        // Removing a journal that's already gone is fine
+        journal.remove().unwrap();
+    }
+
+    #[test]
+    fn load_rejects_other_discs_and_corruption() {
+        let disc = test_disc(test_image());
+        let output = output_path("journal-reject");
+        let path = Journal::path_for(&output);
+        let cases = [
+            "gameid: BLUS-99998\ntotal_sectors: 40\ncompleted: 20\n".to_string(),
+            format!("gameid: {}\ntotal_sectors: 41\ncompleted: 20\n", disc.gameid),
+            format!("gameid: {}\ntotal_sectors: 40\ncompleted: 41\n", disc.gameid),
+            format!("gameid: {}\ntotal_sectors: 40\ncompleted: lots\n", disc.gameid),
+            format!("gameid: {}\ntotal_sectors: 40\n", disc.gameid),
+            String::new()
+        ];
+        let results: Vec<bool> = cases.iter().map(|text| {
+            write_journal(&path, text);
+            Journal::load(&disc, &output).is_err()
+        }).collect();
+        fs::remove_file(&path).ok();
+        assert_eq!(results, vec![true; cases.len()]);
+    }
+
+    #[test]
+    fn validate() {
+        let mut disc = test_disc(test_image());
+        let mut journal = Journal::new(&disc, output_path("journal-validate"));
+        // Nothing to check yet
+        assert!(journal.validate(&mut disc, &mut Cursor::new(vec![])).unwrap());
+
+        journal.completed = 20;
+        assert!(journal.validate(&mut disc, &mut Cursor::new(decrypted(20))).unwrap());
+        assert!(journal.validate(&mut disc, &mut Cursor::new(decrypted(40))).unwrap());
+        // Too short
+        assert!(!journal.validate(&mut disc, &mut Cursor::new(decrypted(19))).unwrap());
+        let mut output = decrypted(20);
+        output.pop();
+        assert!(!journal.validate(&mut disc, &mut Cursor::new(output)).unwrap());
+        // Last sector's wrong
+        let mut output = decrypted(20);
+        output[19 * 2048 + 100] ^= 1;
+        assert!(!journal.validate(&mut disc, &mut Cursor::new(output)).unwrap());
+        // Journal's for the first sectors but the output's all zeroes
+        assert!(!journal.validate(&mut disc, &mut Cursor::new(vec![0u8; 40 * 2048])).unwrap());
+    }
+}
@@ -0,0 +1,344 @@
+use super::errors::*;
+use std::fs::{self, File};
+use std::io::{Read, Seek, Write, ErrorKind as IoErrorKind};
+use std::path::{Path, PathBuf};
+use crypto::md5::Md5;
+use crypto::digest::Digest;
+use disc::{self, PS3Disc};
+use keyfile::{KeyFile, parse_key, format_key};
+use config;
+
+/// Identify a specific disc, so different revisions of a game with the same game ID can be told apart
+///
+/// This is the first 8 bytes of an MD5 of the disc's first two sectors, as hex.
+/// Those have the region layout and game ID in them, and are never encrypted,
+/// so it can be calculated without a key, or from an IRD file's header.
+/// Any 3k3y data in sector 1 is left out, so a 3k3y image has the same fingerprint as the disc it came from.
+pub fn fingerprint<F: Read+Seek>(disc: &mut PS3Disc<F>) -> Result<String> {
+    let mut md5 = Md5::new();
+    for sector in 0..2 {
+        let mut buf = disc.read_sector_raw(sector).chain_err(|| format!("Failed to read sector {}", sector))?;
+        if sector == 1 && disc.tagline_3k3y.is_some() {
+            disc::strip_3k3y(&mut buf);
+        }
+        md5.input(buf.as_ref());
+    }
+    let mut hash = [0u8; 16];
+    md5.result(&mut hash);
+    Ok(hash[..8].iter().map(|b| format!("{:02X}", b)).collect())
+}
+
+/// Game IDs are stored without the dash, ex BLUS30418, so they match IRD files
+pub fn normalize_game_id(game_id: &str) -> String {
+    game_id.replace('-', "").to_uppercase()
+}
+
+/// A single disc's keys in the key database
+#[derive(Debug, Clone)]
+pub struct KeyEntry {
+    /// Game ID, without the dash
+    pub game_id: String,
+    /// Disc fingerprint, see `fingerprint`
+    pub fingerprint: String,
+    /// The disc's d1 key, if it's known
+    pub d1: Option<[u8; 16]>,
+    /// The disc key
+    pub disc_key: [u8; 16]
+}
+
+impl KeyEntry {
+    /// Set this entry's keys on a disc, the same way a key file's are
+    pub fn apply<F: Read+Seek>(&self, disc: &mut PS3Disc<F>) -> Result<()> {
+        KeyFile {
+            d1: self.d1,
+            disc_key: Some(self.disc_key)
+        }.apply(disc)
+    }
+
+    /// Parse a line of a key database file, `num` being its line number for errors
+    ///
+    /// Blank lines and comments give `None`.
+    fn parse(num: usize, line: &str) -> Result<Option<Self>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 4 {
+            bail!("line {}: expected 4 fields, got {}", num, fields.len());
+        }
+        Ok(Some(KeyEntry {
+            game_id: fields[0].to_string(),
+            fingerprint: fields[1].to_string(),
+            d1: if fields[2] == "-" {
+                None
+            } else {
+                Some(parse_key(fields[2]).chain_err(|| format!("line {}: bad d1", num))?)
+            },
+            disc_key: parse_key(fields[3]).chain_err(|| format!("line {}: bad disc key", num))?
+        }))
+    }
+}
+
+/// Local database of disc keys
+///
+/// It's a text file with one disc per line, as `GAMEID FINGERPRINT D1 DISC_KEY`.
+/// D1 is `-` if it isn't known. Blank lines and lines starting with `#` are ignored,
+/// and they aren't kept when the database is saved again, since it's rewritten from the entries.
+#[derive(Debug, Clone, Default)]
+pub struct KeyDatabase {
+    pub entries: Vec<KeyEntry>
+}
+
+impl KeyDatabase {
+    /// Parse the contents of a key database file
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut entries = vec![];
+        for (num, line) in text.lines().enumerate() {
+            if let Some(entry) = KeyEntry::parse(num+1, line)? {
+                entries.push(entry);
+            }
+        }
+        Ok(KeyDatabase {
+            entries
+        })
+    }
+
+    /// Parse the contents of a key database file, skipping any bad lines
+    ///
+    /// Returns what's left, along with what was wrong with each line that was skipped.
+    pub fn parse_lenient(text: &str) -> (Self, Vec<Error>) {
+        let mut entries = vec![];
+        let mut bad_lines = vec![];
+        for (num, line) in text.lines().enumerate() {
+            match KeyEntry::parse(num+1, line) {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => {},
+                Err(e) => bad_lines.push(e)
+            }
+        }
+        (KeyDatabase { entries }, bad_lines)
+    }
+
+    /// Write out the key database file's contents
+    pub fn to_text(&self) -> String {
+        let mut text = String::from("# ps3bdtool key database, rewritten whenever it changes so any other comments will be lost\n# GAMEID FINGERPRINT D1 DISC_KEY\n");
+        for entry in &self.entries {
+            text.push_str(&format!("{} {} {} {}\n",
+                                   entry.game_id,
+                                   entry.fingerprint,
+                                   entry.d1.as_ref().map_or("-".to_string(), format_key),
+                                   format_key(&entry.disc_key)));
+        }
+        text
+    }
+
+    /// Read the key database file, if there is one
+    fn read() -> Result<Option<(PathBuf, String)>> {
+        let path = match config::key_database_path()? {
+            Some(path) => path,
+            None => return Ok(None)
+        };
+        let mut text = String::new();
+        match File::open(&path) {
+            Ok(mut f) => f.read_to_string(&mut text).chain_err(|| "Failed to read key database")?,
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).chain_err(|| "Failed to open key database")
+        };
+        Ok(Some((path, text)))
+    }
+
+    /// Load the key database, which is empty if it doesn't exist yet
+    ///
+    /// Any bad line is an error, so nothing gets lost when it's saved again.
+    pub fn load() -> Result<Self> {
+        match KeyDatabase::read()? {
+            Some((path, text)) => KeyDatabase::parse(text.as_ref()).chain_err(|| format!("Failed to parse key database {}", path.display())),
+            None => Ok(KeyDatabase::default())
+        }
+    }
+
+    /// Load the key database for looking keys up in, skipping any bad lines
+    ///
+    /// See `parse_lenient`.
+    pub fn load_lenient() -> Result<(Self, Vec<Error>)> {
+        match KeyDatabase::read()? {
+            Some((_, text)) => Ok(KeyDatabase::parse_lenient(text.as_ref())),
+            None => Ok((KeyDatabase::default(), vec![]))
+        }
+    }
+
+    /// Save the key database, returning where it was saved to
+    ///
+    /// Only the entries are saved, comments from when it was loaded aren't kept.
+    pub fn save(&self) -> Result<PathBuf> {
+        let path = match config::key_database_path()? {
+            Some(path) => path,
+            None => bail!("No key database location on this platform")
+        };
+        self.save_to(&path)?;
+        Ok(path)
+    }
+
+    /// Save the key database to a specific file
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        // Write to a temporary file and move it into place, so a crash can't leave the database half-written
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        File::create(&tmp_path).chain_err(|| "Failed to create key database")?
+            .write_all(self.to_text().as_bytes()).chain_err(|| "Failed to write key database")?;
+        fs::rename(&tmp_path, path).chain_err(|| "Failed to write key database")
+    }
+
+    /// Find the keys for a disc
+    pub fn find(&self, game_id: &str, fingerprint: &str) -> Option<&KeyEntry> {
+        let game_id = normalize_game_id(game_id);
+        self.entries.iter().find(|e| e.game_id == game_id && e.fingerprint == fingerprint)
+    }
+
+    /// Add a disc's keys, replacing any existing entry for the same disc
+    ///
+    /// Returns whether an existing entry was replaced.
+    pub fn add(&mut self, entry: KeyEntry) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|e| !(e.game_id == entry.game_id && e.fingerprint == entry.fingerprint));
+        let replaced = self.entries.len() != before;
+        self.entries.push(entry);
+        self.entries.sort_by(|a, b| (&a.game_id, &a.fingerprint).cmp(&(&b.game_id, &b.fingerprint)));
+        replaced
+    }
+
+    /// Remove every entry for a game ID, or just the one with a given fingerprint
+    ///
+    /// Returns how many entries were removed.
+    pub fn remove(&mut self, game_id: &str, fingerprint: Option<&str>) -> usize {
+        let game_id = normalize_game_id(game_id);
+        let before = self.entries.len();
+        self.entries.retain(|e| !(e.game_id == game_id && fingerprint.map_or(true, |f| e.fingerprint.eq_ignore_ascii_case(f))));
+        before - self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disc::tests::{test_disc, test_image};
+    use std::{env, process};
+
+    const KEY_A: &str = "00112233445566778899AABBCCDDEEFF";
+    const KEY_B: &str = "FFEEDDCCBBAA99887766554433221100";
+
+    fn entry(game_id: &str, fingerprint: &str) -> KeyEntry {
+        KeyEntry {
+            game_id: game_id.to_string(),
+            fingerprint: fingerprint.to_string(),
+            d1: None,
+            disc_key: parse_key(KEY_A).unwrap()
+        }
+    }
+
+    fn text() -> String {
+        format!("# some keys\n\nBLUS30418 0123456789ABCDEF {} {}\n  # indented comment\nBCES00001 FEDCBA9876543210 - {}\n", KEY_A, KEY_B, KEY_B)
+    }
+
+    #[test]
+    fn parse() {
+        let db = KeyDatabase::parse(&text()).unwrap();
+        assert_eq!(db.entries.len(), 2);
+        assert_eq!(db.entries[0].game_id, "BLUS30418");
+        assert_eq!(db.entries[0].fingerprint, "0123456789ABCDEF");
+        assert_eq!(db.entries[0].d1, Some(parse_key(KEY_A).unwrap()));
+        assert_eq!(db.entries[0].disc_key, parse_key(KEY_B).unwrap());
+        assert_eq!(db.entries[1].d1, None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let db = KeyDatabase::parse(&text()).unwrap();
+        let again = KeyDatabase::parse(&db.to_text()).unwrap();
+        assert_eq!(again.to_text(), db.to_text());
+        assert_eq!(again.entries.len(), 2);
+    }
+
+    #[test]
+    fn bad_lines() {
+        let text = format!("{}BLUS30418 0123456789ABCDEF {}\nBLUS30419 0123456789ABCDEF - NOTHEX\nBLUS30420 0123456789ABCDEF XX {}\n", text(), KEY_A, KEY_A);
+        let e = KeyDatabase::parse(&text).unwrap_err();
+        assert!(e.to_string().starts_with("line 6:"), "{}", e);
+
+        let (db, bad_lines) = KeyDatabase::parse_lenient(&text);
+        assert_eq!(db.entries.len(), 2);
+        let bad_lines: Vec<String> = bad_lines.iter().map(|e| e.to_string()).collect();
+        assert_eq!(bad_lines.len(), 3);
+        assert!(bad_lines[0].starts_with("line 6: expected 4 fields"), "{}", bad_lines[0]);
+        assert!(bad_lines[1].starts_with("line 7: bad disc key"), "{}", bad_lines[1]);
+        assert!(bad_lines[2].starts_with("line 8: bad d1"), "{}", bad_lines[2]);
+    }
+
+    #[test]
+    fn find() {
+        let db = KeyDatabase::parse(&text()).unwrap();
+        assert_eq!(db.find("BLUS-30418", "0123456789ABCDEF").unwrap().disc_key, parse_key(KEY_B).unwrap());
+        assert_eq!(db.find("blus30418", "0123456789ABCDEF").unwrap().game_id, "BLUS30418");
+        // Same game, different disc
+        assert!(db.find("BLUS30418", "FEDCBA9876543210").is_none());
+        assert!(db.find("BLUS30419", "0123456789ABCDEF").is_none());
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let mut db = KeyDatabase::default();
+        assert!(!db.add(entry("BLUS30418", "BBBB")));
+        assert!(!db.add(entry("BLUS30418", "AAAA")));
+        assert!(!db.add(entry("BCES00001", "AAAA")));
+        let mut replacement = entry("BLUS30418", "AAAA");
+        replacement.disc_key = parse_key(KEY_B).unwrap();
+        assert!(db.add(replacement));
+        assert_eq!(db.entries.iter().map(|e| (e.game_id.as_str(), e.fingerprint.as_str())).collect::<Vec<_>>(),
+                   vec![("BCES00001", "AAAA"), ("BLUS30418", "AAAA"), ("BLUS30418", "BBBB")]);
+        assert_eq!(db.find("BLUS30418", "AAAA").unwrap().disc_key, parse_key(KEY_B).unwrap());
+
+        assert_eq!(db.remove("BLUS-30418", Some("bbbb")), 1);
+        assert_eq!(db.remove("BLUS30418", Some("CCCC")), 0);
+        assert_eq!(db.remove("BCES00001", None), 1);
+        assert_eq!(db.remove("BLUS30418", None), 1);
+        assert!(db.entries.is_empty());
+    }
+
+    #[test]
+    fn save_to() {
+        let db = KeyDatabase::parse(&text()).unwrap();
+        let path = env::temp_dir().join(format!("ps3bdtool-test-{}-keys.txt", process::id()));
+        db.save_to(&path).unwrap();
+        let mut saved = String::new();
+        File::open(&path).unwrap().read_to_string(&mut saved).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(saved, db.to_text());
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        assert!(!Path::new(&tmp_path).exists());
+    }
+
+    #[test]
+    fn fingerprint_ignores_3k3y_data() {
+        let image = test_image();
+        let expected = fingerprint(&mut test_disc(image.clone())).unwrap();
+        assert_eq!(expected.len(), 16);
+
+        let mut stripped = image.clone();
+        disc::strip_3k3y(&mut stripped[2048..4096]);
+        assert_eq!(fingerprint(&mut test_disc(stripped)).unwrap(), expected);
+        let mut with_keys = image.clone();
+        disc::inject_3k3y(&mut with_keys[2048..4096], true, &[0x11; 16], &[0x22; 16]);
+        assert_eq!(fingerprint(&mut test_disc(with_keys)).unwrap(), expected);
+
+        // Everything else in the first two sectors counts
+        let mut other_game = image.clone();
+        other_game[2064..2074].copy_from_slice(b"BLUS-99998");
+        assert!(fingerprint(&mut test_disc(other_game)).unwrap() != expected);
+        let mut other_layout = image.clone();
+        other_layout[8 + 2 * 4 + 3] = 31;
+        assert!(fingerprint(&mut test_disc(other_layout)).unwrap() != expected);
+    }
+}
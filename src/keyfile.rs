@@ -0,0 +1,244 @@
+use super::errors::*;
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use hex::FromHex;
+use disc::PS3Disc;
+
+/// Keys loaded from a key file
+///
+/// Two formats are supported:
+///
+/// * Redump-style `.dkey` files, which are just the disc key,
+///   either as 16 raw bytes or as a string of hex bytes
+/// * Key text files, with one `name: value` line per key, ex `d1: 0123...` and `disc_key: 4567...`.
+///   This is the same format `ps3bdtool info --keys` prints.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeyFile {
+    /// The disc's d1 key, used to compute the disc key
+    pub d1: Option<[u8; 16]>,
+    /// The disc key itself
+    pub disc_key: Option<[u8; 16]>
+}
+
+/// Parse a string of hex bytes into a 16 byte key
+pub(crate) fn parse_key(hex: &str) -> Result<[u8; 16]> {
+    let bytes: Vec<u8> = FromHex::from_hex(hex.trim().as_bytes().to_owned()).chain_err(|| "failed to parse key")?;
+    if bytes.len() != 16 {
+        bail!("expected key length 16, got length {}", bytes.len());
+    }
+    let mut key = [0u8; 16];
+    key.copy_from_slice(bytes.as_ref());
+    Ok(key)
+}
+
+/// Format a key as a string of hex bytes
+pub(crate) fn format_key(key: &[u8; 16]) -> String {
+    key.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+impl KeyFile {
+    /// Parse the contents of a key file
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        // Binary .dkey
+        if buf.len() == 16 {
+            let mut disc_key = [0u8; 16];
+            disc_key.copy_from_slice(buf);
+            return Ok(KeyFile {
+                d1: None,
+                disc_key: Some(disc_key)
+            });
+        }
+
+        let text = String::from_utf8_lossy(buf);
+        if !text.contains(':') {
+            // Hex .dkey
+            return Ok(KeyFile {
+                d1: None,
+                disc_key: Some(parse_key(text.as_ref()).chain_err(|| "Failed to parse .dkey file")?)
+            });
+        }
+
+        let mut keys = KeyFile::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next().unwrap().trim();
+            let value = parts.next().unwrap_or("");
+            match name {
+                "d1" => keys.d1 = Some(parse_key(value).chain_err(|| "Failed to parse d1")?),
+                "disc_key" => keys.disc_key = Some(parse_key(value).chain_err(|| "Failed to parse disc_key")?),
+                _ => {}
+            }
+        }
+        if keys.d1.is_none() && keys.disc_key.is_none() {
+            bail!("No d1 or disc_key found in key file");
+        }
+        Ok(keys)
+    }
+
+    /// Get the keys out of a disc, if it has any
+    pub fn from_disc<F: Read+Seek>(disc: &PS3Disc<F>) -> Self {
+        KeyFile {
+            d1: disc.d1,
+            disc_key: disc.disc_key
+        }
+    }
+
+    /// Set the keys on a disc
+    ///
+    /// The disc key takes precedence over d1, same as `--key` and `--d1`.
+    pub fn apply<F: Read+Seek>(&self, disc: &mut PS3Disc<F>) -> Result<()> {
+        if let Some(disc_key) = self.disc_key {
+            disc.set_disc_key(disc_key.as_ref())
+        } else if let Some(d1) = self.d1 {
+            disc.set_d1(d1.as_ref())
+        } else {
+            bail!("Key file has no keys in it");
+        }
+    }
+
+    /// Contents of a `.dkey` file for these keys, which is the disc key as hex bytes
+    pub fn to_dkey(&self) -> Result<String> {
+        match self.disc_key {
+            Some(disc_key) => Ok(format_key(&disc_key)),
+            None => bail!("No disc key to write out")
+        }
+    }
+
+    /// Contents of a key text file for these keys
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        if let Some(d1) = self.d1 {
+            text.push_str(&format!("d1: {}\n", format_key(&d1)));
+        }
+        if let Some(disc_key) = self.disc_key {
+            text.push_str(&format!("disc_key: {}\n", format_key(&disc_key)));
+        }
+        text
+    }
+}
+
+/// Read a key file
+pub fn read_key_file<P: AsRef<Path>>(path: P) -> Result<KeyFile> {
+    let mut buf = vec![];
+    File::open(path.as_ref()).chain_err(|| "Failed to open key file")?
+        .read_to_end(&mut buf).chain_err(|| "Failed to read key file")?;
+    KeyFile::parse(buf.as_ref()).chain_err(|| format!("Failed to parse key file {}", path.as_ref().display()))
+}
+
+/// Write a key file, as a `.dkey` if that's the extension and a key text file otherwise
+pub fn write_key_file<P: AsRef<Path>>(path: P, keys: &KeyFile) -> Result<()> {
+    let path = path.as_ref();
+    let contents = if path.extension().map_or(false, |e| e == "dkey") {
+        keys.to_dkey()?
+    } else {
+        keys.to_text()
+    };
+    File::create(path).chain_err(|| "Failed to create key file")?
+        .write_all(contents.as_bytes()).chain_err(|| "Failed to write key file")
+}
+
+/// Find a key file next to a disc image, with the same name but a `.dkey` or `.key` extension
+///
+/// ex `game.iso` looks for `game.dkey` and `game.key`
+pub fn find_key_file<P: AsRef<Path>>(image: P) -> Option<PathBuf> {
+    let image = image.as_ref();
+    for extension in &["dkey", "key"] {
+        let path = image.with_extension(extension);
+        if path != image && path.is_file() {
+            return Some(path);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    const KEY: [u8; 16] = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF];
+    const KEY_HEX: &str = "00112233445566778899AABBCCDDEEFF";
+    const D1: [u8; 16] = [0x5A; 16];
+
+    #[test]
+    fn binary_dkey() {
+        let keys = KeyFile::parse(&KEY).unwrap();
+        assert_eq!(keys.d1, None);
+        assert_eq!(keys.disc_key, Some(KEY));
+    }
+
+    #[test]
+    fn hex_dkey() {
+        for text in &[KEY_HEX.to_string(), KEY_HEX.to_lowercase(), format!("{}\n", KEY_HEX), format!("{} \r\n", KEY_HEX)] {
+            let keys = KeyFile::parse(text.as_bytes()).unwrap();
+            assert_eq!(keys.d1, None);
+            assert_eq!(keys.disc_key, Some(KEY), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn text() {
+        let text = format!("# keys\nd1: {}\n\ndisc_key:{}\ngame_id: BLUS99999\n", format_key(&D1), KEY_HEX.to_lowercase());
+        let keys = KeyFile::parse(text.as_bytes()).unwrap();
+        assert_eq!(keys.d1, Some(D1));
+        assert_eq!(keys.disc_key, Some(KEY));
+
+        let keys = KeyFile::parse(format!("d1: {}\n", format_key(&D1)).as_bytes()).unwrap();
+        assert_eq!(keys.d1, Some(D1));
+        assert_eq!(keys.disc_key, None);
+
+        assert!(KeyFile::parse(b"game_id: BLUS99999\n").is_err());
+    }
+
+    #[test]
+    fn wrong_length() {
+        assert!(KeyFile::parse(&KEY[..15]).is_err());
+        assert!(KeyFile::parse(KEY_HEX[..30].as_bytes()).is_err());
+        assert!(KeyFile::parse(format!("{}00", KEY_HEX).as_bytes()).is_err());
+        assert!(KeyFile::parse(format!("disc_key: {}\n", &KEY_HEX[..30]).as_bytes()).is_err());
+        assert!(KeyFile::parse(b"disc_key: not hex\n").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let keys = KeyFile { d1: Some(D1), disc_key: Some(KEY) };
+        assert_eq!(keys.to_dkey().unwrap(), KEY_HEX);
+        let parsed = KeyFile::parse(keys.to_dkey().unwrap().as_bytes()).unwrap();
+        assert_eq!(parsed.disc_key, Some(KEY));
+        assert_eq!(parsed.d1, None);
+
+        let parsed = KeyFile::parse(keys.to_text().as_bytes()).unwrap();
+        assert_eq!(parsed.d1, Some(D1));
+        assert_eq!(parsed.disc_key, Some(KEY));
+
+        let d1_only = KeyFile { d1: Some(D1), disc_key: None };
+        assert!(d1_only.to_dkey().is_err());
+        assert_eq!(KeyFile::parse(d1_only.to_text().as_bytes()).unwrap().d1, Some(D1));
+    }
+
+    #[test]
+    fn files() {
+        let dir = env::temp_dir().join(format!("ps3bdtool-test-{}-keyfile", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("game.iso");
+        File::create(&image).unwrap();
+        assert_eq!(find_key_file(&image), None);
+
+        let keys = KeyFile { d1: Some(D1), disc_key: Some(KEY) };
+        write_key_file(dir.join("game.key"), &keys).unwrap();
+        assert_eq!(find_key_file(&image), Some(dir.join("game.key")));
+        write_key_file(dir.join("game.dkey"), &keys).unwrap();
+        assert_eq!(find_key_file(&image), Some(dir.join("game.dkey")));
+
+        let dkey = read_key_file(dir.join("game.dkey")).unwrap();
+        let text = read_key_file(dir.join("game.key")).unwrap();
+        fs::remove_dir_all(&dir).ok();
+        assert_eq!((dkey.d1, dkey.disc_key), (None, Some(KEY)));
+        assert_eq!((text.d1, text.disc_key), (Some(D1), Some(KEY)));
+    }
+}
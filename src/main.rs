@@ -35,10 +35,23 @@ macro_rules! hex_println {
 
 pub mod sector;
 pub mod disc;
+pub mod reader;
+pub mod shared;
 pub mod decrypt;
+pub mod cache;
 #[cfg(unix)] pub mod mountvfs;
 pub mod config;
+pub mod keyfile;
+pub mod keydb;
 pub mod ird;
+pub mod iso9660;
+pub mod sfo;
+pub mod sfb;
+pub mod verify;
+pub mod journal;
+pub mod output;
+pub mod parallel;
+pub mod rescue;
 pub mod commands;
 
 use std::fs::File;
@@ -53,6 +66,19 @@ pub mod errors {
                 description("rust-crypto SymmetricCipherError")
                 display("{:?}", t)
             }
+            IRDParseError(field: &'static str, offset: usize) {
+                description("failed to parse IRD file")
+                display("Failed to parse IRD file: bad or truncated {} field at byte offset {} ({:#X})", field, offset, offset)
+            }
+            UnsupportedIRDVersion(version: u8) {
+                description("unsupported IRD file version")
+                display("IRD file is version {}, only versions {} through {} are supported",
+                        version, ::ird::IRD_MIN_VERSION, ::ird::IRD_MAX_VERSION)
+            }
+            IRDChecksumMismatch(expected: u32, calculated: u32) {
+                description("IRD file failed its CRC32 check")
+                display("IRD file failed its CRC32 check (file says {:08X}, calculated {:08X}), it's probably corrupted", expected, calculated)
+            }
         }
     }
 }
@@ -72,6 +98,9 @@ fn run() -> Result<()> {
             (@arg FILE: +required "File to print information about")
             (@arg id: -i --id "Just print game ID, nothing else")
             (@arg keys: -k --keys "Print the game's decryption keys")
+            (@arg export: --export +takes_value "Write the game's decryption keys to a file, as just the disc key if it ends in .dkey")
+            (@arg irdfile: --ird +takes_value "IRD file to extract keys from")
+            (@arg keyfile: --keyfile +takes_value "Key file to load the disc key or d1 from, either a .dkey or a key text file")
         )
         (@subcommand decrypt =>
             (about: "Decrypt a game iso")
@@ -80,8 +109,87 @@ fn run() -> Result<()> {
             (@arg OUTFILE: "Output file, defaults to <input>.dec.iso")
             (@arg d1: -d --d1 +takes_value "Game's d1 value as a string of hex bytes, used to calculate the disc key")
             (@arg key: -k --key +takes_value "Decryption key as a string of hex bytes")
+            (@arg keyfile: --keyfile +takes_value "Key file to load the disc key or d1 from, either a .dkey or a key text file")
             (@arg threads: -j --threads +takes_value "Number of threads to decrypt with. Defaults to 1. Set to 1 to switch to singlethreaded mode")
+            (@arg resume: -r --resume "Resume an interrupted decrypt into the same output file")
+            (@arg irdfile: --ird +takes_value "IRD file to extract key from")
+        )
+        (@subcommand rip =>
+            (about: "Rip a disc as-is, carrying on past read errors")
+            (@setting ArgRequiredElseHelp)
+            (@arg FILE: +required "Disc drive to rip from, ex /dev/sr0")
+            (@arg OUTFILE: "Output file, defaults to <gameid>.iso")
+            (@arg map: --map +takes_value "Map file tracking bad sectors, defaults to <output>.map")
+            (@arg retries: --retries +takes_value "Number of times to retry a bad sector. Defaults to 3")
+        )
+        (@subcommand encrypt =>
+            (about: "Re-encrypt a decrypted game iso, giving back the original dump")
+            (@setting ArgRequiredElseHelp)
+            (@arg FILE: +required "Decrypted file to encrypt")
+            (@arg OUTFILE: "Output file, defaults to <input>.enc.iso")
+            (@arg d1: -d --d1 +takes_value "Game's d1 value as a string of hex bytes, used to calculate the disc key")
+            (@arg key: -k --key +takes_value "Decryption key as a string of hex bytes")
+            (@arg keyfile: --keyfile +takes_value "Key file to load the disc key or d1 from, either a .dkey or a key text file")
+            (@arg irdfile: --ird +takes_value "IRD file to extract key from")
+        )
+        (@subcommand convert =>
+            (about: "Inject 3k3y data into a game iso so it can decrypt itself, or strip it back out")
+            (@setting ArgRequiredElseHelp)
+            (@arg FILE: +required "Game image to convert")
+            (@arg OUTFILE: "Output file, defaults to <input>.3k3y.iso, or <input>.plain.iso with --strip")
+            (@arg irdfile: --ird +takes_value "IRD file to take the d1 and d2 keys from")
+            (@arg decrypted: --decrypted "The image has already been decrypted, so tag it as such")
+            (@arg strip: --strip conflicts_with[irdfile decrypted] "Remove 3k3y data instead of adding it")
+        )
+        (@subcommand extract =>
+            (about: "Extract a game's files, decrypting them on the way")
+            (@setting ArgRequiredElseHelp)
+            (@arg FILE: +required "Game image or disc drive to extract from")
+            (@arg OUTDIR: +required "Directory to extract to")
+            (@arg only: --only +takes_value +multiple number_of_values(1) "Only extract this path, ex PS3_GAME/USRDIR. Can be given more than once")
+            (@arg d1: -d --d1 +takes_value "Game's d1 value as a string of hex bytes, used to calculate the disc key")
+            (@arg key: -k --key +takes_value "Decryption key as a string of hex bytes")
+            (@arg keyfile: --keyfile +takes_value "Key file to load the disc key or d1 from, either a .dkey or a key text file")
             (@arg irdfile: --ird +takes_value "IRD file to extract key from")
+            (@arg decrypted: --decrypted "The image has already been decrypted")
+        )
+        (@subcommand verify =>
+            (about: "Check a disc's regions against the hashes in its IRD file")
+            (@setting ArgRequiredElseHelp)
+            (@arg FILE: +required "Game image to verify, encrypted or decrypted")
+            (@arg irdfile: --ird +takes_value "IRD file to check against, and to extract the key from")
+            (@arg d1: -d --d1 +takes_value "Game's d1 value as a string of hex bytes, used to calculate the disc key")
+            (@arg key: -k --key +takes_value "Decryption key as a string of hex bytes")
+            (@arg keyfile: --keyfile +takes_value "Key file to load the disc key or d1 from, either a .dkey or a key text file")
+            (@arg decrypted: --decrypted "The image has already been decrypted")
+            (@arg files: --files "Check each file against the IRD's file hashes, instead of whole regions")
+        )
+        (@subcommand keys =>
+            (about: "Manage the local key database")
+            (@setting SubcommandRequiredElseHelp)
+            (@subcommand list =>
+                (about: "List every disc in the key database")
+            )
+            (@subcommand add =>
+                (about: "Add a disc's keys to the key database")
+                (@setting ArgRequiredElseHelp)
+                (@arg FILE: +required "Game image or disc drive to add")
+                (@arg d1: -d --d1 +takes_value "Game's d1 value as a string of hex bytes, used to calculate the disc key")
+                (@arg key: -k --key +takes_value "Decryption key as a string of hex bytes")
+                (@arg keyfile: --keyfile +takes_value "Key file to load the disc key or d1 from, either a .dkey or a key text file")
+                (@arg irdfile: --ird +takes_value "IRD file to extract key from")
+            )
+            (@subcommand remove =>
+                (about: "Remove a game's keys from the key database")
+                (@setting ArgRequiredElseHelp)
+                (@arg GAMEID: +required "Game ID to remove, ex BLUS30418")
+                (@arg FINGERPRINT: "Only remove the disc with this fingerprint")
+            )
+            (@subcommand import =>
+                (about: "Add the keys from IRD files to the key database")
+                (@setting ArgRequiredElseHelp)
+                (@arg IRD: +required +multiple "IRD files to import")
+            )
         )
         (@subcommand irdinfo =>
             (about: "Print information about a 3k3y IRD file")
@@ -97,8 +205,12 @@ fn run() -> Result<()> {
             (@arg FILE: +required "Path to game image or disc drive")
             (@arg MOUNTPOINT: +required "Path to mount to")
             (@arg verbose: -v --verbose "Output debugging information")
+            (@arg files: -f --files "Mount the game's files directly, instead of a GameDisc.iso")
+            (@arg cache: -c --cache +takes_value "How much decrypted data to keep cached, in MiB (default 64, 0 turns it off)")
+            (@arg threads: -j --threads +takes_value "Number of threads to read and decrypt with. Defaults to 4")
             (@arg d1: -d --d1 +takes_value "Game's d1 value as a string of hex bytes, used to calculate the disc key")
             (@arg key: -k --key +takes_value "Decryption key as a string of hex bytes")
+            (@arg keyfile: --keyfile +takes_value "Key file to load the disc key or d1 from, either a .dkey or a key text file")
             (@arg irdfile: --ird +takes_value "IRD file to extract key from")
         ));
     }
@@ -106,6 +218,9 @@ fn run() -> Result<()> {
     match matches.subcommand() {
         ("info", Some(matches)) => commands::info::disc_info(matches)?,
         ("decrypt", Some(matches)) => commands::decrypt::decrypt_disc(matches)?,
+        ("encrypt", Some(matches)) => commands::encrypt::encrypt_disc(matches)?,
+        ("rip", Some(matches)) => commands::rip::rip_disc(matches)?,
+        ("convert", Some(matches)) => commands::convert::convert_disc(matches)?,
         #[cfg(unix)]
         ("mount", Some(matches)) => {
             println!("disc: {}", PathBuf::from(matches.value_of("FILE").unwrap()).display());
@@ -121,9 +236,27 @@ fn run() -> Result<()> {
                 return Ok(());
             }
 
-            mountvfs::mount(disc, matches.value_of("MOUNTPOINT").unwrap(), matches.is_present("verbose"));
+            let cache_size = matches.value_of("cache").unwrap_or("64").parse::<u64>().chain_err(|| "--cache must be a number of MiB")?;
+            if cache_size > 0 {
+                let bytes = match cache_size.checked_mul(1024 * 1024) {
+                    Some(bytes) => bytes,
+                    None => bail!("--cache of {} MiB is too big", cache_size)
+                };
+                disc.set_cache(cache::SectorCache::with_size(bytes));
+            }
+
+            let threads = matches.value_of("threads").unwrap_or("4").parse::<usize>().chain_err(|| "--threads must be a number")?;
+
+            if matches.is_present("files") {
+                mountvfs::mount_files(disc, matches.value_of("MOUNTPOINT").unwrap(), matches.is_present("verbose"), threads)?;
+            } else {
+                mountvfs::mount(disc, matches.value_of("MOUNTPOINT").unwrap(), matches.is_present("verbose"), threads);
+            }
         },
         ("irdinfo", Some(matches)) => commands::info::ird_info(matches)?,
+        ("keys", Some(matches)) => commands::keys::keys(matches)?,
+        ("extract", Some(matches)) => commands::extract::extract_disc(matches)?,
+        ("verify", Some(matches)) => commands::verify::verify_disc(matches)?,
         (_, _) => unreachable!()
     }
     Ok(())
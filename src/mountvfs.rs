@@ -1,20 +1,174 @@
 use fuse::{self, Filesystem, FileAttr, FileType, Request, ReplyAttr, ReplyDirectory, ReplyEntry, ReplyData};
 use disc::PS3Disc;
-use std::io::{Read, Seek};
+use reader::DecryptedReader;
+use shared::SharedDisc;
+use iso9660::{self, DirectoryRecord, Extent};
+use errors::*;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::cmp;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use std::path::Path;
 use std::ffi::OsStr;
-use libc::{ENOENT};
+use std::collections::HashMap;
+use libc::{c_int, ENOENT, ENOTDIR, EIO, EINVAL};
 use time::Timespec;
 
+/// Read `size` bytes of a disc image starting at `offset`, for a FUSE read
+///
+/// Reads don't have to line up with sectors, and ones running past the end of the image
+/// come back short, or empty if they start past it, like reading a normal file would.
+fn read_image<R: Read+Seek>(image: &mut R, image_size: u64, offset: i64, size: u32) -> io::Result<Vec<u8>> {
+    if offset < 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid read at a negative offset"));
+    }
+    let offset = offset as u64;
+    if offset >= image_size {
+        return Ok(vec![]);
+    }
+    let len = cmp::min(size as u64, image_size - offset);
+    let mut data = Vec::with_capacity(len as usize);
+    image.seek(SeekFrom::Start(offset))?;
+    image.take(len).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Error code to reply to FUSE with for a failed read
+///
+/// Asking for something nonsensical is EINVAL, anything else is the disc's fault.
+fn errno(e: &io::Error) -> c_int {
+    if e.kind() == io::ErrorKind::InvalidInput { EINVAL } else { EIO }
+}
+
+/// Read `size` bytes of a file made up of `extents`, starting `offset` bytes into the file
+///
+/// Same as `read_image`, reads running past the end of the file come back short.
+fn read_extents<R: Read+Seek>(image: &mut R, extents: &[Extent], offset: u64, size: u32) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size as usize);
+    let mut extent_start = 0u64;
+    for extent in extents {
+        let wanted = size as u64 - data.len() as u64;
+        if wanted == 0 {
+            break;
+        }
+        let pos = offset + data.len() as u64;
+        if pos < extent_start + extent.length {
+            let within = pos - extent_start;
+            let len = cmp::min(wanted, extent.length - within);
+            image.seek(SeekFrom::Start(extent.start as u64 * 2048 + within))?;
+            if (image.take(len).read_to_end(&mut data)? as u64) < len {
+                break;
+            }
+        }
+        extent_start += extent.length;
+    }
+    Ok(data)
+}
+
+/// How many reads to do between printing the cache stats in verbose mode
+const CACHE_STATS_INTERVAL: u64 = 1000;
+
+/// Print the disc's sector cache hits and misses, for verbose mode
+fn print_cache_stats<F: Read+Seek>(disc: &SharedDisc<F>) {
+    if let Ok(disc) = disc.lock() {
+        if let Some(cache) = disc.cache() {
+            println!("cache: {}", cache);
+        }
+    }
+}
+
+/// A read for one of the `ReadPool` threads to do
+enum ReadRequest {
+    /// Part of the whole decrypted image
+    Image { offset: i64, size: u32 },
+    /// Part of a file on the disc
+    File { ino: u64, extents: Vec<Extent>, offset: u64, size: u32 }
+}
+
+/// Threads that do reads and reply to them, so FUSE can get on with other requests in the meantime
+///
+/// Each thread has its own `DecryptedReader`, so they only wait on each other
+/// to read raw sectors off the disc, see `SharedDisc`.
+struct ReadPool {
+    jobs: Option<mpsc::Sender<(ReadRequest, ReplyData)>>,
+    workers: Vec<thread::JoinHandle<()>>
+}
+
+impl ReadPool {
+    fn new<F: Read+Seek+Send+'static>(disc: &Arc<SharedDisc<F>>, threads: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<(ReadRequest, ReplyData)>();
+        let rx = Arc::new(Mutex::new(rx));
+        let mut workers = vec![];
+        for _ in 0..cmp::max(threads, 1) {
+            let (disc, rx) = (Arc::clone(disc), Arc::clone(&rx));
+            workers.push(thread::spawn(move || {
+                let image_size = disc.total_sectors as u64 * 2048;
+                let mut reader = DecryptedReader::shared(Arc::clone(&disc));
+                loop {
+                    let job = match rx.lock() {
+                        Ok(rx) => rx.recv(),
+                        Err(_) => break
+                    };
+                    // The pool's gone, so the disc's been unmounted
+                    let (request, reply) = match job {
+                        Ok(job) => job,
+                        Err(_) => break
+                    };
+                    let result = match request {
+                        ReadRequest::Image { offset, size } => read_image(&mut reader, image_size, offset, size),
+                        ReadRequest::File { ref extents, offset, size, .. } => read_extents(&mut reader, extents, offset, size)
+                    };
+                    match result {
+                        Ok(data) => reply.data(&data),
+                        Err(e) => {
+                            match request {
+                                ReadRequest::Image { offset, .. } => println!("error reading the iso at offset {}: {}", offset, e),
+                                ReadRequest::File { ino, .. } => println!("error reading inode {}: {}", ino, e)
+                            }
+                            reply.error(errno(&e));
+                        }
+                    }
+                }
+            }));
+        }
+        ReadPool {
+            jobs: Some(tx),
+            workers
+        }
+    }
+
+    /// Hand a read off to the threads, which will reply to it
+    fn read(&self, request: ReadRequest, reply: ReplyData) {
+        if let Some(ref jobs) = self.jobs {
+            if let Err(mpsc::SendError((_, reply))) = jobs.send((request, reply)) {
+                reply.error(EIO);
+            }
+        }
+    }
+}
+
+impl Drop for ReadPool {
+    fn drop(&mut self) {
+        // The threads finish off any reads that are left, then stop once the channel's closed
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+    }
+}
+
 struct DecryptFilesystem<F> {
-    disc: PS3Disc<F>,
+    disc: Arc<SharedDisc<F>>,
+    pool: ReadPool,
     root_attr: FileAttr,
     iso_attr: FileAttr,
+    /// Number of reads so far, for printing the cache stats every so often
+    reads: u64,
     verbose: bool
 }
 
-impl<F: Read+Seek> DecryptFilesystem<F> {
-    fn new (disc: PS3Disc<F>, verbose: bool) -> Self {
+impl<F: Read+Seek+Send+'static> DecryptFilesystem<F> {
+    fn new (disc: PS3Disc<F>, verbose: bool, threads: usize) -> Self {
         let ts = Timespec::new(0, 0);
         let root_attr = FileAttr {
             ino: 1,
@@ -48,7 +202,10 @@ impl<F: Read+Seek> DecryptFilesystem<F> {
             rdev: 0,
             flags: 0,
         };
+        let disc = Arc::new(SharedDisc::new(disc));
         DecryptFilesystem {
+            pool: ReadPool::new(&disc, threads),
+            reads: 0,
             disc, root_attr, iso_attr, verbose
         }
     }
@@ -100,26 +257,254 @@ impl<F: Read+Seek> Filesystem for DecryptFilesystem<F> {
     fn read(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
         if self.verbose {
             println!("read(ino={}, fh={}, offset={}, size={})", ino, fh, offset, size);
+            self.reads += 1;
+            if self.reads % CACHE_STATS_INTERVAL == 0 {
+                print_cache_stats(&self.disc);
+            }
         }
         if ino != 2 {
             reply.error(ENOENT);
             return;
         }
-        let mut return_buf: Vec<u8> = Vec::with_capacity(size as usize+2048);
-        let starting_sector = offset/2048;
-        let offset_from_start = offset%2048;
-        let ending_sector = (offset+size as i64)/2048;
+        self.pool.read(ReadRequest::Image { offset, size }, reply);
+    }
+    fn destroy(&mut self, _req: &Request) {
         if self.verbose {
-            println!("offset: {}, size: {}, starting: {}, offset_from_start: {}, ending: {}",
-                     offset, size, starting_sector, offset_from_start, ending_sector);
+            print_cache_stats(&self.disc);
         }
-        for i in starting_sector..ending_sector {
-            return_buf.append(&mut self.disc.read_sector(i as u32).unwrap());
+    }
+}
+
+/// Inode number FUSE uses for the root directory
+const ROOT_INO: u64 = 1;
+
+/// Filesystem presenting the disc's own files, transparently decrypted
+///
+/// Inode numbers are the position of each file's directory record on the disc,
+/// except for the root directory which has to be 1.
+struct GameFilesystem<F> {
+    disc: Arc<SharedDisc<F>>,
+    pool: ReadPool,
+    /// Every file and directory we've come across so far, by inode number
+    records: HashMap<u64, DirectoryRecord>,
+    /// Inode numbers of each directory's parent
+    parents: HashMap<u64, u64>,
+    /// Entries of each directory that's been listed, by inode number
+    listings: HashMap<u64, Vec<DirectoryRecord>>,
+    /// Number of reads so far, for printing the cache stats every so often
+    reads: u64,
+    verbose: bool
+}
+
+impl<F: Read+Seek+Send+'static> GameFilesystem<F> {
+    fn new(mut disc: PS3Disc<F>, verbose: bool, threads: usize) -> Result<Self> {
+        let root = iso9660::root_directory(&mut disc).chain_err(|| "Failed to read the disc's filesystem")?;
+        let mut records = HashMap::new();
+        records.insert(ROOT_INO, root);
+        let mut parents = HashMap::new();
+        parents.insert(ROOT_INO, ROOT_INO);
+        let disc = Arc::new(SharedDisc::new(disc));
+        Ok(GameFilesystem {
+            pool: ReadPool::new(&disc, threads),
+            listings: HashMap::new(),
+            reads: 0,
+            disc, records, parents, verbose
+        })
+    }
+}
+
+impl<F: Read+Seek> GameFilesystem<F> {
+    /// Inode number for a directory record
+    fn ino(record: &DirectoryRecord) -> u64 {
+        record.position
+    }
+
+    fn attr(ino: u64, record: &DirectoryRecord) -> FileAttr {
+        let ts = Timespec::new(record.recorded_timestamp(), 0);
+        FileAttr {
+            ino,
+            size: record.size,
+            blocks: (record.size + 511) / 512,
+            atime: ts,
+            mtime: ts,
+            ctime: ts,
+            crtime: ts,
+            kind: if record.is_dir() {FileType::Directory} else {FileType::RegularFile},
+            perm: if record.is_dir() {0o555} else {0o444},
+            nlink: if record.is_dir() {2} else {1},
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
         }
-        reply.data(&return_buf[offset_from_start as usize..(offset_from_start as usize+size as usize)]);
+    }
+
+    /// List a directory, remembering everything in it so later getattr/read calls can find them
+    ///
+    /// The disc can't change, so each directory is only read once.
+    fn list(&mut self, ino: u64) -> Result<&[DirectoryRecord]> {
+        if !self.listings.contains_key(&ino) {
+            let dir = match self.records.get(&ino) {
+                Some(dir) => dir.clone(),
+                None => bail!("unknown inode {}", ino)
+            };
+            let entries = iso9660::read_directory(&mut *self.disc.lock()?, &dir)?;
+            for entry in &entries {
+                if entry.is_dir() {
+                    self.parents.insert(Self::ino(entry), ino);
+                }
+                self.records.insert(Self::ino(entry), entry.clone());
+            }
+            self.listings.insert(ino, entries);
+        }
+        Ok(&self.listings[&ino])
     }
 }
 
-pub fn mount<F: Read+Seek, P: AsRef<Path>>(disc: PS3Disc<F>, mountpoint: P, verbose: bool) {
-    fuse::mount(DecryptFilesystem::new(disc, verbose), &mountpoint, &[]).unwrap();
-}
\ No newline at end of file
+impl<F: Read+Seek> Filesystem for GameFilesystem<F> {
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        let ttl = Timespec::new(1000, 0);
+        match self.records.get(&ino) {
+            Some(record) => reply.attr(&ttl, &Self::attr(ino, record)),
+            None => reply.error(ENOENT)
+        }
+    }
+    fn readdir(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        if self.verbose {
+            println!("readdir(ino={}, fh={}, offset={})", ino, fh, offset);
+        }
+        match self.records.get(&ino) {
+            Some(record) if !record.is_dir() => return reply.error(ENOTDIR),
+            None => return reply.error(ENOENT),
+            _ => {}
+        }
+        let parent = self.parents.get(&ino).cloned().unwrap_or(ROOT_INO);
+        let mut all = vec![(ino, FileType::Directory, ".".to_string()), (parent, FileType::Directory, "..".to_string())];
+        match self.list(ino) {
+            Ok(entries) => {
+                for entry in entries {
+                    let kind = if entry.is_dir() {FileType::Directory} else {FileType::RegularFile};
+                    all.push((Self::ino(entry), kind, entry.name.clone()));
+                }
+            },
+            Err(e) => {
+                println!("error reading directory {}: {}", ino, e);
+                return reply.error(EIO);
+            }
+        }
+        for (i, (entry_ino, kind, name)) in all.into_iter().enumerate().skip(offset as usize) {
+            // add returns true once the reply buffer is full
+            if reply.add(entry_ino, (i+1) as i64, kind, &Path::new(&name)) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if self.verbose {
+            println!("lookup(parent={}, name={:?})", parent, name);
+        }
+        match self.records.get(&parent) {
+            Some(record) if !record.is_dir() => return reply.error(ENOTDIR),
+            None => return reply.error(ENOENT),
+            _ => {}
+        }
+        let entries = match self.list(parent) {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("error reading directory {}: {}", parent, e);
+                return reply.error(EIO);
+            }
+        };
+        let ttl = Timespec::new(1, 0);
+        match entries.iter().find(|e| OsStr::new(&e.name) == name) {
+            Some(entry) => reply.entry(&ttl, &Self::attr(Self::ino(entry), entry), 0),
+            None => reply.error(ENOENT)
+        }
+    }
+    fn read(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        if self.verbose {
+            println!("read(ino={}, fh={}, offset={}, size={})", ino, fh, offset, size);
+            self.reads += 1;
+            if self.reads % CACHE_STATS_INTERVAL == 0 {
+                print_cache_stats(&self.disc);
+            }
+        }
+        let extents = match self.records.get(&ino) {
+            Some(record) => record.extents.clone(),
+            None => return reply.error(ENOENT)
+        };
+        if offset < 0 {
+            return reply.error(EINVAL);
+        }
+        self.pool.read(ReadRequest::File { ino, extents, offset: offset as u64, size }, reply);
+    }
+    fn destroy(&mut self, _req: &Request) {
+        if self.verbose {
+            print_cache_stats(&self.disc);
+        }
+    }
+}
+
+/// Mount a decrypted iso of the whole disc, with `threads` threads doing the reads
+pub fn mount<F: Read+Seek+Send+'static, P: AsRef<Path>>(disc: PS3Disc<F>, mountpoint: P, verbose: bool, threads: usize) {
+    fuse::mount(DecryptFilesystem::new(disc, verbose, threads), &mountpoint, &[]).unwrap();
+}
+
+/// Mount the disc's own filesystem, instead of an iso of the whole disc
+pub fn mount_files<F: Read+Seek+Send+'static, P: AsRef<Path>>(disc: PS3Disc<F>, mountpoint: P, verbose: bool, threads: usize) -> Result<()> {
+    let fs = GameFilesystem::new(disc, verbose, threads)?;
+    fuse::mount(fs, &mountpoint, &[]).chain_err(|| "Failed to mount")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disc::tests::{test_disc, test_image};
+
+    /// A reader over `image`, and the whole thing decrypted to check reads against
+    fn reader(image: Vec<u8>) -> (DecryptedReader<io::Cursor<Vec<u8>>>, Vec<u8>) {
+        let mut decrypted = vec![0u8; 40 * 2048];
+        test_disc(test_image()).read_sectors_into(0, &mut decrypted).unwrap();
+        (DecryptedReader::new(test_disc(image)), decrypted)
+    }
+
+    #[test]
+    fn unaligned_reads() {
+        let (mut reader, decrypted) = reader(test_image());
+        let size = decrypted.len() as u64;
+        for &(offset, len) in &[(0, 100), (100, 2048), (2047, 2), (3 * 2048 + 100, 5000), (1000, 3 * 2048)] {
+            let data = read_image(&mut reader, size, offset as i64, len as u32).unwrap();
+            assert_eq!(&data[..], &decrypted[offset..(offset + len)]);
+        }
+    }
+
+    #[test]
+    fn reads_past_the_end_come_back_short() {
+        let (mut reader, decrypted) = reader(test_image());
+        let size = decrypted.len() as u64;
+        let data = read_image(&mut reader, size, size as i64 - 1000, 4096).unwrap();
+        assert_eq!(&data[..], &decrypted[(decrypted.len() - 1000)..]);
+        assert!(read_image(&mut reader, size, size as i64, 4096).unwrap().is_empty());
+        assert!(read_image(&mut reader, size, size as i64 + 5000, 4096).unwrap().is_empty());
+    }
+
+    #[test]
+    fn negative_offset_is_einval() {
+        let (mut reader, decrypted) = reader(test_image());
+        let e = read_image(&mut reader, decrypted.len() as u64, -1, 4096).unwrap_err();
+        assert_eq!(errno(&e), EINVAL);
+    }
+
+    #[test]
+    fn read_error_is_eio() {
+        // The header says there's 40 sectors, but the image stops halfway
+        let mut image = test_image();
+        image.truncate(20 * 2048);
+        let (mut reader, decrypted) = reader(image);
+        let size = decrypted.len() as u64;
+        assert!(read_image(&mut reader, size, 0, 4096).is_ok());
+        let e = read_image(&mut reader, size, 25 * 2048 + 10, 4096).unwrap_err();
+        assert_eq!(errno(&e), EIO);
+    }
+}
@@ -0,0 +1,32 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Cursor, Write};
+
+/// Output that can be pushed all the way out to the disk, not just flushed to the OS
+///
+/// Anything that records how far it's gotten (a journal, a rip's map file) should only be saved
+/// after this, or a crash could leave it claiming sectors that never made it to the disk.
+pub trait SyncWrite: Write {
+    /// Flush any buffered data and wait until it's actually on the disk
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl SyncWrite for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.sync_data()
+    }
+}
+
+impl<W: SyncWrite> SyncWrite for BufWriter<W> {
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.get_mut().sync()
+    }
+}
+
+/// In memory, so there's nothing to sync
+impl SyncWrite for Cursor<Vec<u8>> {
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
@@ -0,0 +1,351 @@
+use super::errors::*;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use disc::PS3Disc;
+use journal::JOURNAL_INTERVAL;
+
+/// Most sectors to read, decrypt, and write in one go, 4MiB worth
+pub const CHUNK_SECTORS: u32 = 2048;
+
+/// How far along a parallel decrypt is, given to the progress callback
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    /// Number of sectors decrypted and written so far, including any that were skipped at the start
+    pub done: u32,
+    /// Total number of sectors on the disc
+    pub total: u32,
+    /// Set when the output has just been flushed, to the number of sectors from the start of the disc
+    /// that are now safely written. Use it to keep a `Journal` up to date.
+    ///
+    /// With `decrypt_disc_synced`, they've been synced to the disk too.
+    pub flushed: Option<u32>
+}
+
+/// A run of sectors making its way through the pipeline
+struct Chunk {
+    /// Order the chunk was read in, starting from 0
+    index: u64,
+    /// First sector in the chunk
+    sector: u32,
+    /// Number of sectors in the chunk, `buf` can be bigger than this
+    count: u32,
+    buf: Vec<u8>
+}
+
+impl Chunk {
+    fn data(&mut self) -> &mut [u8] {
+        &mut self.buf[..(self.count as usize * 2048)]
+    }
+}
+
+/// Where the chunk starting at `sector` should end (exclusive)
+///
+/// Chunks never cross a region boundary, so each one is either all encrypted or all not.
+fn chunk_end<F>(disc: &PS3Disc<F>, sector: u32) -> u32 {
+    let mut end = disc.total_sectors.min(sector.saturating_add(CHUNK_SECTORS));
+    for region in &disc.regions {
+        if region.within_region(sector) && region.end < end {
+            end = region.end + 1;
+        }
+    }
+    end
+}
+
+/// Decrypt a disc using multiple threads
+///
+/// One thread reads the disc in big contiguous chunks (see `CHUNK_SECTORS`),
+/// the worker threads decrypt those chunks in place, and the calling thread puts them
+/// back in order and writes them to `output` one after the other.
+/// The chunk buffers get passed back around to the reader once they're written,
+/// so nothing is allocated per sector, and only a few chunks are ever in flight.
+///
+/// Decryption starts at `start_sector`, to resume an earlier decrypt.
+///
+/// `progress` is called on the calling thread after each chunk is written.
+/// If it returns an error, the decrypt is stopped and that error returned.
+/// Same goes for any error reading, decrypting, or writing a chunk.
+pub fn decrypt_disc<F, W, P>(disc: PS3Disc<F>, output: &mut W, threads: usize, start_sector: u32, progress: P) -> Result<()>
+    where F: Read+Seek+Send+'static, W: Write+Seek, P: FnMut(&Progress) -> Result<()> {
+    decrypt_disc_synced(disc, output, threads, start_sector, |output| output.flush(), progress)
+}
+
+/// `decrypt_disc`, calling `sync` on the output instead of just flushing it before reporting sectors as `flushed`
+///
+/// Flushing only hands the data to the OS, so a crash can still lose it.
+/// Pass something like `SyncWrite::sync` to wait until it's on the disk.
+pub fn decrypt_disc_synced<F, W, S, P>(mut disc: PS3Disc<F>, output: &mut W, threads: usize, start_sector: u32, mut sync: S, mut progress: P) -> Result<()>
+    where F: Read+Seek+Send+'static, W: Write+Seek, S: FnMut(&mut W) -> io::Result<()>, P: FnMut(&Progress) -> Result<()> {
+    if threads == 0 {
+        bail!("need at least one thread to decrypt with");
+    }
+    let total_sectors = disc.total_sectors;
+    let decryptor = disc.get_decryptor().chain_err(|| "Failed to get standalone disc decryptor")?;
+    let stop = Arc::new(AtomicBool::new(false));
+
+    // Empty buffers go back to the reader through here. How many there are caps
+    // how far the reader and workers can get ahead of the writes.
+    let (pool_tx, pool_rx) = mpsc::channel::<Vec<u8>>();
+    for _ in 0..(threads * 2 + 2) {
+        pool_tx.send(vec![0u8; CHUNK_SECTORS as usize * 2048]).unwrap();
+    }
+    // Read chunks waiting to be decrypted
+    let (work_tx, work_rx) = mpsc::channel::<Chunk>();
+    let work_rx = Arc::new(Mutex::new(work_rx));
+    // Decrypted chunks, or whatever went wrong, on their way to be written
+    let (done_tx, done_rx) = mpsc::channel::<Result<Chunk>>();
+
+    let mut threads_running = vec![];
+    {
+        let (stop, done_tx) = (Arc::clone(&stop), done_tx.clone());
+        threads_running.push(thread::spawn(move || {
+            let mut index = 0;
+            let mut sector = start_sector;
+            while sector < disc.total_sectors && !stop.load(Ordering::Relaxed) {
+                // The pool only runs dry for good if the decrypt's being stopped
+                let buf = match pool_rx.recv() {
+                    Ok(buf) => buf,
+                    Err(_) => break
+                };
+                let end = chunk_end(&disc, sector);
+                let mut chunk = Chunk { index, sector, count: end - sector, buf };
+                if let Err(e) = disc.read_sectors_raw_into(sector, chunk.data()) {
+                    let e = Err(e).chain_err(|| format!("Failed to read sectors {}-{}", sector, end - 1));
+                    done_tx.send(e).ok();
+                    break;
+                }
+                if work_tx.send(chunk).is_err() {
+                    break;
+                }
+                index += 1;
+                sector = end;
+            }
+        }));
+    }
+    for _ in 0..threads {
+        let (work_rx, done_tx) = (Arc::clone(&work_rx), done_tx.clone());
+        let decryptor = decryptor.clone();
+        threads_running.push(thread::spawn(move || {
+            loop {
+                let chunk = match work_rx.lock() {
+                    Ok(work_rx) => work_rx.recv(),
+                    Err(_) => break
+                };
+                // The reader's gone, so there's nothing left to decrypt
+                let mut chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(_) => break
+                };
+                let (sector, end) = (chunk.sector, chunk.sector + chunk.count);
+                let decrypted = decryptor.decrypt_sectors_in_place(chunk.data(), sector)
+                    .chain_err(|| format!("Failed to decrypt sectors {}-{}", sector, end - 1))
+                    .map(|_| chunk);
+                let failed = decrypted.is_err();
+                // The receiving end is only gone if the decrypt's being stopped
+                if done_tx.send(decrypted).is_err() || failed {
+                    break;
+                }
+            }
+        }));
+    }
+    // Only the reader and workers have senders now, so the loop below ends when they're all done
+    drop(done_tx);
+
+    let mut result = output.seek(SeekFrom::Start(start_sector as u64 * 2048))
+        .chain_err(|| "Failed to seek output").map(|_| ());
+    // Chunks that finished before the one that has to be written next
+    let mut waiting: BTreeMap<u64, Chunk> = BTreeMap::new();
+    let mut next_index = 0;
+    let mut done = start_sector;
+    if result.is_ok() {
+        'chunks: for decrypted in done_rx.iter() {
+            match decrypted {
+                Ok(chunk) => { waiting.insert(chunk.index, chunk); },
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+            while let Some(mut chunk) = waiting.remove(&next_index) {
+                if let Err(e) = output.write_all(chunk.data()).chain_err(|| format!("Failed to write sectors {}-{}", chunk.sector, chunk.sector + chunk.count - 1)) {
+                    result = Err(e);
+                    break 'chunks;
+                }
+                let before = done;
+                done += chunk.count;
+                next_index += 1;
+                // The reader might have quit already, that's fine
+                pool_tx.send(chunk.buf).ok();
+
+                let flushed = if done / JOURNAL_INTERVAL != before / JOURNAL_INTERVAL || done == total_sectors {
+                    if let Err(e) = sync(output).chain_err(|| "Failed to sync output") {
+                        result = Err(e);
+                        break 'chunks;
+                    }
+                    Some(done)
+                } else {
+                    None
+                };
+                if let Err(e) = progress(&Progress { done, total: total_sectors, flushed }) {
+                    result = Err(e);
+                    break 'chunks;
+                }
+            }
+        }
+    }
+    // Dropping the pool stops the reader, which in turn stops the workers
+    stop.store(true, Ordering::Relaxed);
+    drop(pool_tx);
+    drop(done_rx);
+
+    for thread in threads_running {
+        if thread.join().is_err() && result.is_ok() {
+            result = Err("a decryption thread crashed".into());
+        }
+    }
+    if result.is_ok() && done != total_sectors {
+        result = Err(format!("only decrypted {} of {} sectors", done, total_sectors).into());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::Cursor;
+    use disc::tests::{test_disc, test_image_with_regions};
+
+    /// Unencrypted regions for a disc a few chunks long, with regions that don't line up with them
+    const REGIONS: &[u32] = &[0, 1, 3000, 4999, 6000, 6999];
+
+    /// The whole of a disc, decrypted one sector at a time
+    fn expected(image: Vec<u8>) -> Vec<u8> {
+        let mut disc = test_disc(image);
+        let mut expected = vec![0u8; disc.total_sectors as usize * 2048];
+        for (i, sector) in expected.chunks_mut(2048).enumerate() {
+            disc.read_sectors_into(i as u32, sector).unwrap();
+        }
+        expected
+    }
+
+    fn decrypt(image: Vec<u8>, threads: usize) -> Vec<u8> {
+        let mut output = Cursor::new(vec![]);
+        decrypt_disc(test_disc(image), &mut output, threads, 0, |_| Ok(())).unwrap();
+        output.into_inner()
+    }
+
+    #[test]
+    fn one_thread_matches_sector_by_sector() {
+        let image = test_image_with_regions(REGIONS);
+        assert!(decrypt(image.clone(), 1) == expected(image));
+    }
+
+    #[test]
+    fn many_threads_match_sector_by_sector() {
+        let image = test_image_with_regions(REGIONS);
+        let expected = expected(image.clone());
+        for &threads in &[2, 4, 8] {
+            assert!(decrypt(image.clone(), threads) == expected, "{} threads", threads);
+        }
+    }
+
+    /// Sectors decrypted so far after each chunk, decrypting from `start_sector`
+    fn chunk_ends(start_sector: u32) -> (Vec<u32>, Vec<u8>) {
+        let mut output = Cursor::new(vec![]);
+        let mut done = vec![];
+        decrypt_disc(test_disc(test_image_with_regions(REGIONS)), &mut output, 3, start_sector, |progress| {
+            done.push(progress.done);
+            Ok(())
+        }).unwrap();
+        (done, output.into_inner())
+    }
+
+    #[test]
+    fn chunks_stop_at_region_boundaries() {
+        let disc = test_disc(test_image_with_regions(REGIONS));
+        assert_eq!(chunk_end(&disc, 0), 2);
+        assert_eq!(chunk_end(&disc, 2), 2 + CHUNK_SECTORS);
+        // The encrypted region ends partway through this window
+        assert_eq!(chunk_end(&disc, 2 + CHUNK_SECTORS), 3000);
+        assert_eq!(chunk_end(&disc, 2999), 3000);
+        assert_eq!(chunk_end(&disc, 3000), 5000);
+        assert_eq!(chunk_end(&disc, 6500), 7000);
+
+        let (done, _) = chunk_ends(0);
+        assert_eq!(done, vec![2, 2 + CHUNK_SECTORS, 3000, 5000, 6000, 7000]);
+    }
+
+    #[test]
+    fn resumes_partway_through_a_chunk() {
+        let image = test_image_with_regions(REGIONS);
+        let expected = expected(image);
+        let (done, output) = chunk_ends(2500);
+        assert_eq!(done, vec![3000, 5000, 6000, 7000]);
+        assert_eq!(output.len(), expected.len());
+        // Nothing before the start sector gets written
+        assert!(output[..(2500 * 2048)].iter().all(|&b| b == 0));
+        assert!(&output[(2500 * 2048)..] == &expected[(2500 * 2048)..]);
+    }
+
+    #[test]
+    fn resuming_a_finished_decrypt_does_nothing() {
+        let (done, output) = chunk_ends(7000);
+        assert!(done.is_empty());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn no_threads_is_an_error() {
+        let mut output = Cursor::new(vec![]);
+        assert!(decrypt_disc(test_disc(test_image_with_regions(REGIONS)), &mut output, 0, 0, |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn read_errors_come_back() {
+        // The header says there's 7000 sectors, but the image stops partway through
+        let mut image = test_image_with_regions(REGIONS);
+        image.truncate(4000 * 2048);
+        let mut output = Cursor::new(vec![]);
+        let mut last_done = 0;
+        let e = decrypt_disc(test_disc(image), &mut output, 4, 0, |progress| {
+            last_done = progress.done;
+            Ok(())
+        }).unwrap_err();
+        assert!(e.to_string().starts_with("Failed to read sectors"), "{}", e);
+        assert!(last_done < 4000);
+    }
+
+    #[test]
+    fn progress_errors_stop_the_decrypt() {
+        let mut output = Cursor::new(vec![]);
+        let mut calls = 0;
+        let e = decrypt_disc(test_disc(test_image_with_regions(REGIONS)), &mut output, 4, 0, |_| {
+            calls += 1;
+            bail!("stop right there")
+        }).unwrap_err();
+        assert_eq!(e.to_string(), "stop right there");
+        assert_eq!(calls, 1);
+        // Only the first chunk was written before stopping
+        assert_eq!(output.into_inner().len(), 2 * 2048);
+    }
+
+    #[test]
+    fn syncs_before_reporting_flushed() {
+        let mut output = Cursor::new(vec![]);
+        let syncs = Cell::new(0);
+        let mut flushed = vec![];
+        decrypt_disc_synced(test_disc(test_image_with_regions(REGIONS)), &mut output, 2, 0, |_| {
+            syncs.set(syncs.get() + 1);
+            Ok(())
+        }, |progress| {
+            if let Some(sectors) = progress.flushed {
+                flushed.push((sectors, syncs.get()));
+            }
+            Ok(())
+        }).unwrap();
+        assert_eq!(flushed, vec![(7000, 1)]);
+    }
+}
@@ -0,0 +1,173 @@
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use disc::PS3Disc;
+use shared::SharedDisc;
+
+/// `Read` and `Seek` over a disc's decrypted image, with byte offsets instead of sectors
+///
+/// Hand this to anything that wants to read a decrypted .iso, and sectors get
+/// read and decrypted as needed. Reads of whole sectors go straight into the caller's buffer,
+/// anything smaller is served out of the last sector read.
+///
+/// The disc is shared, so several readers (on different threads even) can each have their own position on it.
+pub struct DecryptedReader<F> {
+    disc: Arc<SharedDisc<F>>,
+    /// Current position, in bytes from the start of the disc
+    pos: u64,
+    /// Which sector is in `buf`, if any
+    buffered: Option<u32>,
+    buf: [u8; 2048]
+}
+
+fn to_io_error<E: ::std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+impl<F: Read+Seek> DecryptedReader<F> {
+    /// Start reading a disc from the beginning
+    ///
+    /// Set its key and cache first.
+    pub fn new(disc: PS3Disc<F>) -> Self {
+        DecryptedReader::shared(Arc::new(SharedDisc::new(disc)))
+    }
+
+    /// Start reading a shared disc from the beginning, for when other readers are using it too
+    pub fn shared(disc: Arc<SharedDisc<F>>) -> Self {
+        DecryptedReader {
+            disc,
+            pos: 0,
+            buffered: None,
+            buf: [0u8; 2048]
+        }
+    }
+
+    /// Size of the decrypted image in bytes
+    fn size(&self) -> u64 {
+        self.disc.total_sectors as u64 * 2048
+    }
+}
+
+impl<F: Read+Seek> Read for DecryptedReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.size();
+        if self.pos >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let sector = (self.pos / 2048) as u32;
+        let offset = (self.pos % 2048) as usize;
+
+        if offset == 0 && buf.len() >= 2048 {
+            // Whole sectors, so skip the copy
+            let sectors = cmp::min(buf.len() / 2048, ((size - self.pos) / 2048) as usize);
+            let len = sectors * 2048;
+            self.disc.read_sectors_into(sector, &mut buf[..len]).map_err(to_io_error)?;
+            self.pos += len as u64;
+            return Ok(len);
+        }
+
+        if self.buffered != Some(sector) {
+            // Don't leave a half-read sector marked as good if this fails
+            self.buffered = None;
+            self.disc.read_sectors_into(sector, &mut self.buf).map_err(to_io_error)?;
+            self.buffered = Some(sector);
+        }
+        let len = cmp::min(buf.len(), 2048 - offset);
+        buf[..len].copy_from_slice(&self.buf[offset..(offset+len)]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<F: Read+Seek> Seek for DecryptedReader<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => self.size() as i64 + n,
+            SeekFrom::Current(n) => self.pos as i64 + n
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disc::tests::{test_disc, test_image};
+
+    fn reader() -> (DecryptedReader<io::Cursor<Vec<u8>>>, Vec<u8>) {
+        let mut decrypted = vec![0u8; 40 * 2048];
+        test_disc(test_image()).read_sectors_into(0, &mut decrypted).unwrap();
+        (DecryptedReader::new(test_disc(test_image())), decrypted)
+    }
+
+    #[test]
+    fn small_reads_across_sector_boundaries() {
+        let (mut reader, decrypted) = reader();
+        reader.seek(SeekFrom::Start(2048 - 7)).unwrap();
+        let mut data = vec![];
+        // Odd sized reads, so they keep landing partway through sectors
+        let mut buf = [0u8; 13];
+        while data.len() < 3 * 2048 {
+            let len = reader.read(&mut buf).unwrap();
+            assert!(len > 0 && len <= 13);
+            data.extend_from_slice(&buf[..len]);
+        }
+        assert!(&data[..] == &decrypted[(2048 - 7)..(2048 - 7 + data.len())]);
+    }
+
+    #[test]
+    fn whole_sector_reads() {
+        let (mut reader, decrypted) = reader();
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert!(data == decrypted);
+
+        let mut buf = vec![0u8; 3 * 2048 + 100];
+        reader.seek(SeekFrom::Start(5 * 2048)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert!(&buf[..] == &decrypted[(5 * 2048)..(8 * 2048 + 100)]);
+    }
+
+    #[test]
+    fn seeking() {
+        let (mut reader, decrypted) = reader();
+        let size = decrypted.len() as u64;
+        assert_eq!(reader.seek(SeekFrom::End(-100)).unwrap(), size - 100);
+        assert_eq!(reader.seek(SeekFrom::Current(-1948)).unwrap(), size - 2048);
+        assert_eq!(reader.seek(SeekFrom::Current(10)).unwrap(), size - 2038);
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &decrypted[(decrypted.len() - 2038)..(decrypted.len() - 2034)]);
+        assert_eq!(reader.seek(SeekFrom::Current(0)).unwrap(), size - 2034);
+    }
+
+    #[test]
+    fn negative_seeks_are_errors() {
+        let (mut reader, _) = reader();
+        reader.seek(SeekFrom::Start(100)).unwrap();
+        for &pos in &[SeekFrom::Current(-101), SeekFrom::End(-(40 * 2048) - 1)] {
+            assert_eq!(reader.seek(pos).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        // A failed seek doesn't move anything
+        assert_eq!(reader.seek(SeekFrom::Current(0)).unwrap(), 100);
+    }
+
+    #[test]
+    fn reads_at_the_end() {
+        let (mut reader, decrypted) = reader();
+        let size = decrypted.len() as u64;
+        let mut buf = [0u8; 4096];
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 10);
+        assert_eq!(&buf[..10], &decrypted[(decrypted.len() - 10)..]);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        // Seeking past the end is allowed, there's just nothing there
+        assert_eq!(reader.seek(SeekFrom::End(5000)).unwrap(), size + 5000);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,407 @@
+use super::errors::*;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write, ErrorKind as IoErrorKind};
+use std::path::{Path, PathBuf};
+use output::SyncWrite;
+
+/// How many sectors to try reading at once, before falling back to one at a time
+pub const CHUNK_SECTORS: u32 = 32;
+
+/// How many sectors to rip between map file saves
+pub const MAP_SAVE_INTERVAL: u32 = 16384;
+
+/// What we know about a single sector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectorStatus {
+    /// Haven't tried to read it yet
+    NonTried,
+    /// Tried to read it and failed, there's zeros in the output for now
+    BadSector,
+    /// Read it successfully
+    Finished
+}
+
+impl SectorStatus {
+    /// ddrescue's character for this status
+    fn to_char(self) -> char {
+        match self {
+            SectorStatus::NonTried => '?',
+            SectorStatus::BadSector => '-',
+            SectorStatus::Finished => '+'
+        }
+    }
+
+    /// ddrescue's status characters
+    ///
+    /// ddrescue has a few more than we do for its different passes,
+    /// which all count as bad sectors since they weren't completely read.
+    fn from_char(c: char) -> Option<Self> {
+        match c {
+            '?' => Some(SectorStatus::NonTried),
+            '*' | '/' | '-' => Some(SectorStatus::BadSector),
+            '+' => Some(SectorStatus::Finished),
+            _ => None
+        }
+    }
+}
+
+/// ddrescue-style map file, tracking which sectors of a rip are good, bad, or not tried yet
+///
+/// The file has a status line, then one line per run of sectors with the same status,
+/// as `position size status` with the position and size in bytes as hex.
+/// So, it can be read by ddrescue's tools (ddrescuelog and friends),
+/// and maps written by ddrescue with 2048 byte sectors can be read back in.
+#[derive(Debug, Clone)]
+pub struct RescueMap {
+    /// Where the map file lives
+    pub path: PathBuf,
+    /// Status of every sector
+    pub sectors: Vec<SectorStatus>
+}
+
+fn parse_hex(s: &str) -> Result<u64> {
+    let s = s.trim_left_matches("0x").trim_left_matches("0X");
+    u64::from_str_radix(s, 16).chain_err(|| format!("bad number {}", s))
+}
+
+impl RescueMap {
+    /// Start a new map with nothing tried yet
+    pub fn new<P: AsRef<Path>>(path: P, total_sectors: u32) -> Self {
+        RescueMap {
+            path: path.as_ref().to_path_buf(),
+            sectors: vec![SectorStatus::NonTried; total_sectors as usize]
+        }
+    }
+
+    /// Load a map file, if there is one
+    pub fn load<P: AsRef<Path>>(path: P, total_sectors: u32) -> Result<Option<Self>> {
+        let mut text = String::new();
+        match File::open(path.as_ref()) {
+            Ok(mut f) => f.read_to_string(&mut text).chain_err(|| "Failed to read map file")?,
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).chain_err(|| "Failed to open map file")
+        };
+
+        let mut map = RescueMap::new(path, total_sectors);
+        // The first line that isn't a comment is the status line, which we don't need
+        let lines = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')).skip(1);
+        for line in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 {
+                bail!("Bad map file line: {}", line);
+            }
+            let pos = parse_hex(fields[0])?;
+            let size = parse_hex(fields[1])?;
+            let status = match SectorStatus::from_char(fields[2].chars().next().unwrap()) {
+                Some(status) => status,
+                None => bail!("Bad map file status: {}", fields[2])
+            };
+            if pos % 2048 != 0 || size % 2048 != 0 {
+                bail!("Map file isn't in 2048 byte sectors: {}", line);
+            }
+            let start = (pos / 2048) as usize;
+            let end = ((pos + size) / 2048) as usize;
+            if end > map.sectors.len() {
+                bail!("Map file is for a bigger disc than this one");
+            }
+            for sector in &mut map.sectors[start..end] {
+                *sector = status;
+            }
+        }
+        Ok(Some(map))
+    }
+
+    /// Runs of sectors with the same status, as `(first sector, number of sectors, status)`
+    pub fn runs(&self) -> Vec<(u32, u32, SectorStatus)> {
+        let mut runs: Vec<(u32, u32, SectorStatus)> = vec![];
+        for (sector, &status) in self.sectors.iter().enumerate() {
+            if let Some(last) = runs.last_mut() {
+                if last.2 == status {
+                    last.1 += 1;
+                    continue;
+                }
+            }
+            runs.push((sector as u32, 1, status));
+        }
+        runs
+    }
+
+    /// Runs of bad sectors, as `(first sector, number of sectors)`
+    pub fn bad_ranges(&self) -> Vec<(u32, u32)> {
+        self.runs().into_iter().filter(|r| r.2 == SectorStatus::BadSector).map(|r| (r.0, r.1)).collect()
+    }
+
+    /// Number of sectors with a given status
+    pub fn count(&self, status: SectorStatus) -> u32 {
+        self.sectors.iter().filter(|&&s| s == status).count() as u32
+    }
+
+    /// Whether every sector has been read successfully
+    pub fn is_finished(&self) -> bool {
+        self.sectors.iter().all(|&s| s == SectorStatus::Finished)
+    }
+
+    /// Write out the map file
+    pub fn save(&self) -> Result<()> {
+        let mut text = String::from("# Mapfile. Created by ps3bdtool\n# current_pos  current_status\n");
+        text.push_str(&format!("0x{:08X}     {}\n", 0, if self.is_finished() {'+'} else {'?'}));
+        text.push_str("#      pos        size  status\n");
+        for (start, count, status) in self.runs() {
+            text.push_str(&format!("0x{:08X}  0x{:08X}  {}\n", start as u64 * 2048, count as u64 * 2048, status.to_char()));
+        }
+        // Write to a temporary file and move it into place, so the map is never half-written
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        File::create(&tmp_path).chain_err(|| "Failed to create map file")?
+            .write_all(text.as_bytes()).chain_err(|| "Failed to write map file")?;
+        fs::rename(&tmp_path, &self.path).chain_err(|| "Failed to write map file")
+    }
+}
+
+/// Options for a tolerant rip
+#[derive(Debug, Clone, Copy)]
+pub struct RipOptions {
+    /// How many more times to try reading a sector after the first failure
+    pub retries: u32
+}
+
+/// What happened during a rip
+#[derive(Debug, Clone, Default)]
+pub struct RipStats {
+    /// Sectors that were read successfully this time
+    pub read: u32,
+    /// Sectors that still couldn't be read, with the last error for each
+    pub bad: Vec<(u32, String)>
+}
+
+/// Read `count` sectors starting at `sector` into `buf`
+fn read_sectors<R: Read+Seek>(input: &mut R, sector: u32, buf: &mut [u8]) -> io::Result<()> {
+    input.seek(SeekFrom::Start(sector as u64 * 2048))?;
+    input.read_exact(buf)
+}
+
+/// Rip every sector the map doesn't have yet, tolerating read errors
+///
+/// Sectors are read in chunks for speed, and if a chunk fails each of its sectors is tried
+/// on its own, `options.retries` more times. Sectors that still fail are zero-filled
+/// in the output and marked bad in the map, so a later rip with the same map can try them again.
+///
+/// The map is saved as the rip goes and when it's done, after the output is synced to the disk.
+///
+/// `progress` is called with each sector number and its status after it's been tried.
+pub fn rip<R: Read+Seek, W: SyncWrite+Seek, P: FnMut(u32, SectorStatus)>(input: &mut R, output: &mut W, map: &mut RescueMap, options: RipOptions, mut progress: P) -> Result<RipStats> {
+    let total_sectors = map.sectors.len() as u32;
+    let mut stats = RipStats::default();
+    let mut buf = vec![0u8; CHUNK_SECTORS as usize * 2048];
+    let mut since_save = 0;
+
+    let mut sector = 0;
+    while sector < total_sectors {
+        if map.sectors[sector as usize] == SectorStatus::Finished {
+            sector += 1;
+            continue;
+        }
+        // Chunk up everything we still need, up to CHUNK_SECTORS
+        let mut count = 1;
+        while count < CHUNK_SECTORS && sector + count < total_sectors
+            && map.sectors[(sector + count) as usize] != SectorStatus::Finished {
+            count += 1;
+        }
+
+        let chunk = &mut buf[..(count as usize * 2048)];
+        if read_sectors(input, sector, chunk).is_ok() {
+            output.seek(SeekFrom::Start(sector as u64 * 2048)).chain_err(|| "Failed to seek output")?;
+            output.write_all(chunk).chain_err(|| "Failed to write output")?;
+            for s in sector..(sector + count) {
+                map.sectors[s as usize] = SectorStatus::Finished;
+                stats.read += 1;
+                progress(s, SectorStatus::Finished);
+            }
+        } else {
+            // Something in the chunk is bad, so go one sector at a time to find out what
+            for s in sector..(sector + count) {
+                let one = &mut chunk[((s - sector) as usize * 2048)..((s - sector + 1) as usize * 2048)];
+                let mut last_error = None;
+                for _ in 0..(options.retries + 1) {
+                    match read_sectors(input, s, one) {
+                        Ok(_) => {
+                            last_error = None;
+                            break;
+                        },
+                        Err(e) => last_error = Some(e)
+                    }
+                }
+                let status = if let Some(e) = last_error {
+                    for byte in one.iter_mut() {
+                        *byte = 0;
+                    }
+                    stats.bad.push((s, e.to_string()));
+                    SectorStatus::BadSector
+                } else {
+                    stats.read += 1;
+                    SectorStatus::Finished
+                };
+                output.seek(SeekFrom::Start(s as u64 * 2048)).chain_err(|| "Failed to seek output")?;
+                output.write_all(one).chain_err(|| "Failed to write output")?;
+                map.sectors[s as usize] = status;
+                progress(s, status);
+            }
+        }
+
+        sector += count;
+        since_save += count;
+        if since_save >= MAP_SAVE_INTERVAL {
+            output.sync().chain_err(|| "Failed to write output")?;
+            map.save()?;
+            since_save = 0;
+        }
+    }
+
+    output.sync().chain_err(|| "Failed to write output")?;
+    map.save()?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::env;
+    use std::io::Cursor;
+    use std::process;
+
+    /// A disc that fails to read chosen sectors a set number of times, and counts every attempt
+    struct FaultyDisc {
+        data: Vec<u8>,
+        pos: u64,
+        /// How many more times each sector should fail
+        failures: HashMap<u32, u32>,
+        /// How many reads each sector has been part of
+        attempts: HashMap<u32, u32>
+    }
+
+    impl FaultyDisc {
+        fn new(sectors: u32) -> Self {
+            let mut data = vec![];
+            for sector in 0..sectors {
+                data.extend(vec![sector as u8 + 1; 2048]);
+            }
+            FaultyDisc {
+                data,
+                pos: 0,
+                failures: HashMap::new(),
+                attempts: HashMap::new()
+            }
+        }
+
+        fn attempts(&self, sector: u32) -> u32 {
+            self.attempts.get(&sector).cloned().unwrap_or(0)
+        }
+    }
+
+    impl Read for FaultyDisc {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let start = self.pos as usize;
+            let end = (start + buf.len()).min(self.data.len());
+            if start >= end {
+                return Ok(0);
+            }
+            let mut failed = false;
+            for sector in ((start / 2048) as u32)..(((end + 2047) / 2048) as u32) {
+                *self.attempts.entry(sector).or_insert(0) += 1;
+                if let Some(left) = self.failures.get_mut(&sector) {
+                    if *left > 0 {
+                        *left -= 1;
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                return Err(io::Error::new(io::ErrorKind::Other, "injected read error"));
+            }
+            buf[..(end - start)].copy_from_slice(&self.data[start..end]);
+            self.pos = end as u64;
+            Ok(end - start)
+        }
+    }
+
+    impl Seek for FaultyDisc {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            match pos {
+                SeekFrom::Start(n) => self.pos = n,
+                _ => unimplemented!()
+            }
+            Ok(self.pos)
+        }
+    }
+
+    fn map_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("ps3bdtool-test-{}-{}.map", process::id(), name))
+    }
+
+    #[test]
+    fn retries_then_zero_fills_bad_sectors() {
+        let mut disc = FaultyDisc::new(40);
+        // Sector 5 comes good on its second try on its own, sector 9 never does
+        disc.failures.insert(5, 2);
+        disc.failures.insert(9, 100);
+        let mut output = Cursor::new(vec![]);
+        let path = map_path("retries");
+        let mut map = RescueMap::new(&path, 40);
+
+        let stats = rip(&mut disc, &mut output, &mut map, RipOptions { retries: 3 }, |_, _| {}).unwrap();
+
+        // Once in the failed chunk, then once on its own for every try
+        assert_eq!(disc.attempts(5), 3);
+        assert_eq!(disc.attempts(9), 1 + 4);
+        // Sectors in the failed chunk are read again on their own, the next chunk isn't affected
+        assert_eq!(disc.attempts(0), 2);
+        assert_eq!(disc.attempts(35), 1);
+
+        assert_eq!(stats.read, 39);
+        assert_eq!(stats.bad.len(), 1);
+        assert_eq!(stats.bad[0].0, 9);
+
+        let output = output.into_inner();
+        assert_eq!(output.len(), 40 * 2048);
+        assert!(output[(9 * 2048)..(10 * 2048)].iter().all(|&b| b == 0));
+        assert_eq!(&output[..(9 * 2048)], &disc.data[..(9 * 2048)]);
+        assert_eq!(&output[(10 * 2048)..], &disc.data[(10 * 2048)..]);
+
+        assert_eq!(map.bad_ranges(), vec![(9, 1)]);
+        assert_eq!(map.count(SectorStatus::Finished), 39);
+        let saved = RescueMap::load(&path, 40).unwrap().unwrap();
+        assert_eq!(saved.sectors, map.sectors);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resumed_rip_only_retries_bad_sectors() {
+        let mut disc = FaultyDisc::new(40);
+        disc.failures.insert(9, 5);
+        disc.failures.insert(33, 5);
+        let mut output = Cursor::new(vec![]);
+        let path = map_path("resume");
+        let mut map = RescueMap::new(&path, 40);
+        rip(&mut disc, &mut output, &mut map, RipOptions { retries: 1 }, |_, _| {}).unwrap();
+        assert_eq!(map.bad_ranges(), vec![(9, 1), (33, 1)]);
+
+        // The drive's having a better day now
+        disc.failures.clear();
+        disc.attempts.clear();
+        let mut map = RescueMap::load(&path, 40).unwrap().unwrap();
+        let mut tried = vec![];
+        let stats = rip(&mut disc, &mut output, &mut map, RipOptions { retries: 1 }, |sector, _| tried.push(sector)).unwrap();
+
+        assert_eq!(tried, vec![9, 33]);
+        assert_eq!(disc.attempts.len(), 2);
+        assert_eq!(disc.attempts(9), 1);
+        assert_eq!(disc.attempts(33), 1);
+        assert_eq!(stats.read, 2);
+        assert!(stats.bad.is_empty());
+        assert!(map.is_finished());
+        assert_eq!(output.into_inner(), disc.data);
+        assert!(RescueMap::load(&path, 40).unwrap().unwrap().is_finished());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+use super::errors::*;
+use std::io::{Read, Seek};
+use disc::PS3Disc;
+use iso9660;
+
+/// Where the disc's SFB file lives on the disc
+pub const DISC_SFB_PATH: &str = "PS3_DISC.SFB";
+
+/// given a four-element &[u8], calculate the big-endian u32 that they represent
+fn be_u32(i: &[u8]) -> u32 {
+    debug_assert_eq!(i.len(), 4, "a u32 is 4 bytes and yet I didn't get 4 bytes");
+    ((i[0] as u32) << 24) + ((i[1] as u32) << 16) + ((i[2] as u32) << 8) + i[3] as u32
+}
+
+/// A PS3_DISC.SFB file, which says what's on the disc
+///
+/// Layout is a 0x20 byte header, then 0x20 byte entries of a 16 byte key, the value's offset
+/// and length, and 8 bytes of padding. Entries end at the first empty key.
+#[derive(Debug, Clone)]
+pub struct SFBFile {
+    /// SFB format version, ex 0x00010000 for 1.0
+    pub version: u32,
+    /// Every key and value, in the order they're in the file
+    pub entries: Vec<(String, String)>
+}
+
+impl SFBFile {
+    /// Parse an SFB file
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 0x20 || &buf[0..4] != b".SFB" {
+            bail!("Magic number .SFB not found, is this an SFB file?");
+        }
+        let version = be_u32(&buf[4..8]);
+
+        let mut entries = vec![];
+        let mut pos = 0x20;
+        while pos + 0x20 <= buf.len() && buf[pos] != 0 {
+            let key = &buf[pos..(pos+16)];
+            let key_end = key.iter().position(|&b| b == 0).unwrap_or(16);
+            let key = String::from_utf8_lossy(&key[..key_end]).to_string();
+
+            // In u64, so a huge offset and length can't overflow
+            let data_start = be_u32(&buf[(pos+16)..(pos+20)]) as u64;
+            let data_end = data_start + be_u32(&buf[(pos+20)..(pos+24)]) as u64;
+            if data_end > buf.len() as u64 {
+                bail!("SFB value for {} runs past the end of the file", key);
+            }
+            let data = &buf[(data_start as usize)..(data_end as usize)];
+            let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+            entries.push((key, String::from_utf8_lossy(&data[..end]).to_string()));
+            pos += 0x20;
+        }
+        Ok(SFBFile {
+            version, entries
+        })
+    }
+
+    /// Get a value by its key, ex "TITLE_ID"
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter().find(|e| e.0 == key).map(|e| e.1.as_ref())
+    }
+}
+
+/// Disc-level metadata, from PS3_DISC.SFB
+#[derive(Debug, Clone)]
+pub struct DiscMetadata {
+    /// SFB format version, ex 0x00010000 for 1.0
+    pub version: u32,
+    /// What's on the disc, one letter per type of content, ex "gu"
+    pub hybrid_flag: String,
+    /// Title ID, ex BLUS-30418
+    pub title_id: String
+}
+
+impl DiscMetadata {
+    /// Pull the metadata out of an SFB file
+    pub fn from_sfb(sfb: &SFBFile) -> Self {
+        DiscMetadata {
+            version: sfb.version,
+            hybrid_flag: sfb.get("HYBRID_FLAG").unwrap_or("").to_string(),
+            title_id: sfb.get("TITLE_ID").unwrap_or("").to_string()
+        }
+    }
+
+    /// Read PS3_DISC.SFB off the disc and pull the metadata out of it
+    pub fn read<F: Read+Seek>(disc: &mut PS3Disc<F>) -> Result<Self> {
+        Ok(DiscMetadata::from_sfb(&read_disc_sfb(disc)?))
+    }
+
+    /// Human-readable names for what the hybrid flag says is on the disc
+    ///
+    /// Letters we don't know about are passed through as-is.
+    pub fn contents(&self) -> Vec<String> {
+        self.hybrid_flag.chars().map(|c| match c {
+            'g' => "game".to_string(),
+            'u' => "system update".to_string(),
+            'v' => "video".to_string(),
+            c => format!("unknown ({})", c)
+        }).collect()
+    }
+
+    /// Whether the disc has a game on it
+    pub fn has_game(&self) -> bool {
+        self.hybrid_flag.contains('g')
+    }
+}
+
+/// Read PS3_DISC.SFB off the disc, decrypting it if needed
+pub fn read_disc_sfb<F: Read+Seek>(disc: &mut PS3Disc<F>) -> Result<SFBFile> {
+    let record = match iso9660::lookup(disc, DISC_SFB_PATH)? {
+        Some(record) => record,
+        None => bail!("{} not found on the disc", DISC_SFB_PATH)
+    };
+    let mut buf = vec![];
+    iso9660::open(disc, &record)?.read_to_end(&mut buf).chain_err(|| format!("Failed to read {}", DISC_SFB_PATH))?;
+    SFBFile::parse(buf.as_ref()).chain_err(|| format!("Failed to parse {}", DISC_SFB_PATH))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disc::tests::{test_disc, write_sectors};
+    use iso9660::tests::{test_iso, SFB_SECTOR};
+
+    /// A 512 byte SFB file with `entries`, values starting at 0x100 in 0x20 byte slots like the real thing
+    fn sfb_bytes(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut buf = vec![0u8; 512];
+        buf[0..4].copy_from_slice(b".SFB");
+        buf[4..8].copy_from_slice(&[0, 1, 0, 0]);
+        for (i, &(key, value)) in entries.iter().enumerate() {
+            let pos = 0x20 + i * 0x20;
+            let data_start = 0x100 + i * 0x20;
+            buf[pos..(pos + key.len())].copy_from_slice(key.as_bytes());
+            buf[(pos + 18)..(pos + 20)].copy_from_slice(&[(data_start >> 8) as u8, data_start as u8]);
+            buf[pos + 23] = 0x10;
+            buf[data_start..(data_start + value.len())].copy_from_slice(value.as_bytes());
+        }
+        buf
+    }
+
+    fn disc_sfb() -> Vec<u8> {
+        sfb_bytes(&[("HYBRID_FLAG", "gu"), ("TITLE_ID", "BLUS-99999")])
+    }
+
+    #[test]
+    fn parse() {
+        let sfb = SFBFile::parse(&disc_sfb()).unwrap();
+        assert_eq!(sfb.version, 0x00010000);
+        assert_eq!(sfb.entries, vec![("HYBRID_FLAG".to_string(), "gu".to_string()),
+                                     ("TITLE_ID".to_string(), "BLUS-99999".to_string())]);
+        assert_eq!(sfb.get("TITLE_ID"), Some("BLUS-99999"));
+        assert_eq!(sfb.get("VERSION"), None);
+    }
+
+    #[test]
+    fn metadata() {
+        let metadata = DiscMetadata::from_sfb(&SFBFile::parse(&disc_sfb()).unwrap());
+        assert_eq!(metadata.hybrid_flag, "gu");
+        assert_eq!(metadata.title_id, "BLUS-99999");
+        assert_eq!(metadata.contents(), vec!["game", "system update"]);
+        assert!(metadata.has_game());
+
+        let metadata = DiscMetadata::from_sfb(&SFBFile::parse(&sfb_bytes(&[("HYBRID_FLAG", "vx")])).unwrap());
+        assert_eq!(metadata.title_id, "");
+        assert_eq!(metadata.contents(), vec!["video", "unknown (x)"]);
+        assert!(!metadata.has_game());
+    }
+
+    #[test]
+    fn bad_files() {
+        let mut bad_magic = disc_sfb();
+        bad_magic[0] = b'!';
+        assert!(SFBFile::parse(&bad_magic).unwrap_err().to_string().starts_with("Magic number"));
+        assert!(SFBFile::parse(&disc_sfb()[..0x1F]).is_err());
+
+        let mut past_the_end = disc_sfb();
+        past_the_end[0x20 + 22] = 0xFF;
+        assert!(SFBFile::parse(&past_the_end).is_err());
+        let mut past_the_end = disc_sfb();
+        past_the_end[0x20 + 16] = 0xFF;
+        assert!(SFBFile::parse(&past_the_end).is_err());
+        // Offset and length that only add up past the end with overflow
+        let mut overflow = disc_sfb();
+        overflow[(0x20 + 16)..(0x20 + 24)].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xF0, 0x00, 0x00, 0x00, 0x20]);
+        assert!(SFBFile::parse(&overflow).is_err());
+    }
+
+    #[test]
+    fn read_from_disc() {
+        let mut image = test_iso();
+        write_sectors(&mut image, SFB_SECTOR, &disc_sfb());
+        let metadata = DiscMetadata::read(&mut test_disc(image)).unwrap();
+        assert_eq!(metadata.version, 0x00010000);
+        assert_eq!(metadata.hybrid_flag, "gu");
+        assert_eq!(metadata.title_id, "BLUS-99999");
+
+        // test_iso's own PS3_DISC.SFB is just noise
+        assert!(DiscMetadata::read(&mut test_disc(test_iso())).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use nom::{le_u16, le_u32, IResult};
+use super::errors::*;
+use std::io::{Read, Seek};
+use disc::PS3Disc;
+use iso9660;
+
+/// Where the game's PARAM.SFO lives on the disc
+pub const PARAM_SFO_PATH: &str = "PS3_GAME/PARAM.SFO";
+
+/// A single value in an SFO file
+#[derive(Debug, Clone, PartialEq)]
+pub enum SFOValue {
+    /// UTF-8 string, with the null terminator removed
+    String(String),
+    /// 32-bit integer
+    Integer(u32),
+    /// Anything else, left as raw bytes
+    Binary(Vec<u8>)
+}
+
+/// A PARAM.SFO file, which is a list of keys and values describing a game
+#[derive(Debug, Clone)]
+pub struct SFOFile {
+    /// Version of the SFO format, 0x101 (1.1) for every PS3 game
+    pub version: u32,
+    /// Every key and value, in the order they're in the file
+    pub entries: Vec<(String, SFOValue)>
+}
+
+/// An entry in the SFO index table, pointing to a key and its value
+struct IndexEntry {
+    key_offset: u16,
+    format: u16,
+    length: u32,
+    data_offset: u32
+}
+
+named!(index_entry<IndexEntry>, do_parse!(
+    key_offset: le_u16 >>
+    format: le_u16 >>
+    length: le_u32 >>
+    le_u32 >> // max length, which is just how much space is reserved
+    data_offset: le_u32 >>
+    (IndexEntry {
+        key_offset, format, length, data_offset
+    })
+));
+
+named!(sfo_header<(u32, u32, u32, Vec<IndexEntry>)>, do_parse!(
+    tag!("\0PSF") >>
+    version: le_u32 >>
+    key_table: le_u32 >>
+    data_table: le_u32 >>
+    count: le_u32 >>
+    entries: count!(index_entry, count as usize) >>
+    ((version, key_table, data_table, entries))
+));
+
+/// Value formats
+const FORMAT_UTF8_SPECIAL: u16 = 0x0004;
+const FORMAT_UTF8: u16 = 0x0204;
+const FORMAT_INTEGER: u16 = 0x0404;
+
+impl SFOFile {
+    /// Parse an SFO file
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        // Don't trust the entry count until it's been checked against how much file there is for them
+        if buf.len() >= 20 {
+            let count = (buf[16] as u64) + ((buf[17] as u64) << 8) + ((buf[18] as u64) << 16) + ((buf[19] as u64) << 24);
+            if 20 + count * 16 > buf.len() as u64 {
+                bail!("SFO file says it has {} entries, but it's too short for that many", count);
+            }
+        }
+        let (version, key_table, data_table, index) = match sfo_header(buf) {
+            IResult::Done(_, header) => header,
+            _ => bail!("Failed to parse SFO header, is this an SFO file?")
+        };
+
+        let mut entries = vec![];
+        for entry in index {
+            let key_start = key_table as u64 + entry.key_offset as u64;
+            if key_start >= buf.len() as u64 {
+                bail!("SFO key offset {} is past the end of the file", key_start);
+            }
+            let key_start = key_start as usize;
+            let key_end = buf[key_start..].iter().position(|&b| b == 0).map_or(buf.len(), |p| key_start + p);
+            let key = String::from_utf8_lossy(&buf[key_start..key_end]).to_string();
+
+            let data_start = data_table as u64 + entry.data_offset as u64;
+            let data_end = data_start + entry.length as u64;
+            if data_end > buf.len() as u64 {
+                bail!("SFO value for {} runs past the end of the file", key);
+            }
+            let data = &buf[(data_start as usize)..(data_end as usize)];
+            let value = match entry.format {
+                FORMAT_UTF8 | FORMAT_UTF8_SPECIAL => {
+                    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                    SFOValue::String(String::from_utf8_lossy(&data[..end]).to_string())
+                },
+                FORMAT_INTEGER if data.len() == 4 => {
+                    SFOValue::Integer((data[0] as u32) + ((data[1] as u32) << 8) + ((data[2] as u32) << 16) + ((data[3] as u32) << 24))
+                },
+                _ => SFOValue::Binary(data.to_vec())
+            };
+            entries.push((key, value));
+        }
+        Ok(SFOFile {
+            version, entries
+        })
+    }
+
+    /// Get a value by its key, ex "TITLE"
+    pub fn get(&self, key: &str) -> Option<&SFOValue> {
+        self.entries.iter().find(|e| e.0 == key).map(|e| &e.1)
+    }
+
+    /// Get a string value by its key, if it's there and is a string
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        match self.get(key) {
+            Some(&SFOValue::String(ref s)) => Some(s),
+            _ => None
+        }
+    }
+
+    /// Get an integer value by its key, if it's there and is an integer
+    pub fn get_int(&self, key: &str) -> Option<u32> {
+        match self.get(key) {
+            Some(&SFOValue::Integer(i)) => Some(i),
+            _ => None
+        }
+    }
+}
+
+/// Read the game's PARAM.SFO off the disc, decrypting it if needed
+pub fn read_param_sfo<F: Read+Seek>(disc: &mut PS3Disc<F>) -> Result<SFOFile> {
+    let record = match iso9660::lookup(disc, PARAM_SFO_PATH)? {
+        Some(record) => record,
+        None => bail!("{} not found on the disc", PARAM_SFO_PATH)
+    };
+    let mut buf = vec![];
+    iso9660::open(disc, &record)?.read_to_end(&mut buf).chain_err(|| format!("Failed to read {}", PARAM_SFO_PATH))?;
+    SFOFile::parse(buf.as_ref()).chain_err(|| format!("Failed to parse {}", PARAM_SFO_PATH))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use disc::tests::{test_disc, write_sectors};
+    use iso9660::tests::{test_iso, SFO_SECTOR};
+
+    fn le16(n: u16) -> [u8; 2] {
+        [n as u8, (n >> 8) as u8]
+    }
+
+    fn le32(n: u32) -> [u8; 4] {
+        [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+    }
+
+    /// An SFO file with the given keys, value formats, and values
+    pub fn sfo_bytes(entries: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let index_end = 20 + entries.len() * 16;
+        let mut keys: Vec<u8> = vec![];
+        let mut data: Vec<u8> = vec![];
+        let mut index: Vec<u8> = vec![];
+        for &(key, format, value) in entries {
+            index.extend(&le16(keys.len() as u16));
+            index.extend(&le16(format));
+            index.extend(&le32(value.len() as u32));
+            index.extend(&le32(value.len() as u32));
+            index.extend(&le32(data.len() as u32));
+            keys.extend(key.as_bytes());
+            keys.push(0);
+            data.extend(value);
+            while data.len() % 4 != 0 {
+                data.push(0);
+            }
+        }
+        while keys.len() % 4 != 0 {
+            keys.push(0);
+        }
+        let mut sfo = b"\0PSF".to_vec();
+        sfo.extend(&le32(0x101));
+        sfo.extend(&le32(index_end as u32));
+        sfo.extend(&le32((index_end + keys.len()) as u32));
+        sfo.extend(&le32(entries.len() as u32));
+        sfo.extend(index);
+        sfo.extend(keys);
+        sfo.extend(data);
+        sfo
+    }
+
+    /// A PARAM.SFO like a game's, cut down
+    pub fn param_sfo() -> Vec<u8> {
+        sfo_bytes(&[
+            ("CATEGORY", FORMAT_UTF8, b"DG\0\0"),
+            ("PARENTAL_LEVEL", FORMAT_INTEGER, &[3, 0, 0, 0]),
+            ("TITLE", FORMAT_UTF8, b"Test Game\0"),
+            ("TITLE_ID", FORMAT_UTF8, b"BLUS99999\0"),
+            ("VERSION", FORMAT_UTF8_SPECIAL, b"01.00\0"),
+            ("WEIRD", 0x0123, &[1, 2, 3])
+        ])
+    }
+
+    #[test]
+    fn parses_a_minimal_sfo() {
+        let sfo = SFOFile::parse(&param_sfo()).unwrap();
+        assert_eq!(sfo.version, 0x101);
+        assert_eq!(sfo.entries.len(), 6);
+        assert_eq!(sfo.get_str("TITLE"), Some("Test Game"));
+        assert_eq!(sfo.get_str("TITLE_ID"), Some("BLUS99999"));
+        assert_eq!(sfo.get_str("CATEGORY"), Some("DG"));
+        assert_eq!(sfo.get_str("VERSION"), Some("01.00"));
+        assert_eq!(sfo.get_int("PARENTAL_LEVEL"), Some(3));
+        assert_eq!(sfo.get("WEIRD"), Some(&SFOValue::Binary(vec![1, 2, 3])));
+        assert_eq!(sfo.get("NOPE"), None);
+
+        let empty = SFOFile::parse(&sfo_bytes(&[])).unwrap();
+        assert!(empty.entries.is_empty());
+    }
+
+    #[test]
+    fn type_mismatches() {
+        let sfo = SFOFile::parse(&param_sfo()).unwrap();
+        assert_eq!(sfo.get_int("TITLE"), None);
+        assert_eq!(sfo.get_str("PARENTAL_LEVEL"), None);
+        assert_eq!(sfo.get_str("WEIRD"), None);
+        assert_eq!(sfo.get_int("WEIRD"), None);
+        // Integers have to be exactly 4 bytes
+        let short = SFOFile::parse(&sfo_bytes(&[("SHORT", FORMAT_INTEGER, &[1, 2])])).unwrap();
+        assert_eq!(short.get_int("SHORT"), None);
+        assert_eq!(short.get("SHORT"), Some(&SFOValue::Binary(vec![1, 2])));
+    }
+
+    #[test]
+    fn offsets_past_the_end() {
+        let sfo = param_sfo();
+        // The first entry's key offset, then its data offset
+        let mut bad_key = sfo.clone();
+        bad_key[20..22].copy_from_slice(&le16(0xFFFF));
+        assert!(SFOFile::parse(&bad_key).is_err());
+        let mut bad_data = sfo.clone();
+        bad_data[32..36].copy_from_slice(&le32(0xFFFF_FFFF));
+        assert!(SFOFile::parse(&bad_data).is_err());
+        let mut bad_length = sfo.clone();
+        bad_length[24..28].copy_from_slice(&le32(0xFFFF_FFF0));
+        assert!(SFOFile::parse(&bad_length).is_err());
+    }
+
+    #[test]
+    fn huge_entry_counts() {
+        for &count in &[7, 0x1000_0000, 0xFFFF_FFFF] {
+            let mut sfo = param_sfo();
+            sfo[16..20].copy_from_slice(&le32(count));
+            assert!(SFOFile::parse(&sfo).is_err(), "{} entries", count);
+        }
+    }
+
+    #[test]
+    fn not_an_sfo() {
+        assert!(SFOFile::parse(b"").is_err());
+        assert!(SFOFile::parse(b"\0PSX\x01\x01\0\0").is_err());
+    }
+
+    #[test]
+    fn reads_param_sfo_off_the_disc() {
+        let mut image = test_iso();
+        write_sectors(&mut image, SFO_SECTOR, &param_sfo());
+        let sfo = read_param_sfo(&mut test_disc(image)).unwrap();
+        assert_eq!(sfo.get_str("TITLE"), Some("Test Game"));
+    }
+}
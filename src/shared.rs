@@ -0,0 +1,101 @@
+use super::errors::*;
+use std::io::{Read, Seek};
+use std::sync::{Mutex, MutexGuard};
+use disc::{PS3Disc, PS3DiscDecryptor};
+
+/// A disc that can be read from several threads at once
+///
+/// The disc itself is behind a lock, but only checking its sector cache and reading raw sectors
+/// happen while holding it. Decrypting is done by whichever thread asked for the sectors,
+/// using a `PS3DiscDecryptor`, so threads only wait on each other for the drive.
+pub struct SharedDisc<F> {
+    disc: Mutex<PS3Disc<F>>,
+    /// Decryptor for the disc, or `None` if the image is already decrypted or there's no key,
+    /// in which case reads go through `PS3Disc::read_sectors_into` as normal
+    decryptor: Option<PS3DiscDecryptor>,
+    /// Total number of sectors on the disc
+    pub total_sectors: u32
+}
+
+impl<F: Read+Seek> SharedDisc<F> {
+    /// Share a disc, set its key and cache first
+    pub fn new(disc: PS3Disc<F>) -> Self {
+        let decryptor = if disc.decrypted { None } else { disc.get_decryptor().ok() };
+        SharedDisc {
+            total_sectors: disc.total_sectors,
+            decryptor,
+            disc: Mutex::new(disc)
+        }
+    }
+
+    /// Lock the disc, for anything that needs to use it directly
+    pub fn lock<'a>(&'a self) -> Result<MutexGuard<'a, PS3Disc<F>>> {
+        self.disc.lock().map_err(|_| "a thread crashed while reading the disc".into())
+    }
+
+    /// Read a run of sectors into `buf`, decrypting as needed
+    ///
+    /// Same as `PS3Disc::read_sectors_into`, including using and filling the disc's cache.
+    pub fn read_sectors_into(&self, start: u32, buf: &mut [u8]) -> Result<()> {
+        let decryptor = match self.decryptor {
+            Some(ref decryptor) => decryptor,
+            None => return self.lock()?.read_sectors_into(start, buf)
+        };
+        // Only the cache and the drive need the lock, decrypting can happen alongside other reads
+        let mut misses = self.lock()?.read_cache_misses(start, buf)?;
+        if misses.is_empty() {
+            return Ok(());
+        }
+        for miss in &mut misses {
+            miss.decrypt_into(start, buf, |first, data| decryptor.decrypt_sectors_in_place(data, first))?;
+        }
+        let mut disc = self.lock()?;
+        for miss in &misses {
+            disc.add_to_cache(miss.first, &miss.data);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::SectorCache;
+    use disc::tests::{test_disc, test_image};
+
+    /// Reads that hit the header, both sides of the encrypted region, and the end,
+    /// going over some of them twice so the second time comes out of the cache
+    const READS: &[(u32, u32)] = &[(1, 1), (0, 2), (1, 3), (5, 1), (0, 8), (28, 4), (36, 4), (3, 30), (0, 40), (1, 1)];
+
+    fn check_reads(cache: bool) {
+        let mut disc = test_disc(test_image());
+        let mut shared_disc = test_disc(test_image());
+        if cache {
+            disc.set_cache(SectorCache::with_size(1024 * 1024));
+            shared_disc.set_cache(SectorCache::with_size(1024 * 1024));
+        }
+        let shared = SharedDisc::new(shared_disc);
+        for &(start, count) in READS {
+            let mut expected = vec![0u8; count as usize * 2048];
+            disc.read_sectors_into(start, &mut expected).unwrap();
+            let mut buf = vec![0u8; count as usize * 2048];
+            shared.read_sectors_into(start, &mut buf).unwrap();
+            assert!(buf == expected, "sectors {} to {} differ", start, start + count - 1);
+            if start <= 1 && start + count > 1 {
+                let tagline = (1 - start) as usize * 2048 + 1904;
+                assert_eq!(&buf[tagline..(tagline + 12)], b"Decrypted 3K");
+            }
+        }
+        assert_eq!(shared.lock().unwrap().cache().is_some(), cache);
+    }
+
+    #[test]
+    fn same_as_ps3disc_without_a_cache() {
+        check_reads(false);
+    }
+
+    #[test]
+    fn same_as_ps3disc_with_a_cache() {
+        check_reads(true);
+    }
+}
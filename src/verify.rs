@@ -0,0 +1,256 @@
+use super::errors::*;
+use std::io::{Read, Seek};
+use std::collections::HashMap;
+use crypto::md5::Md5;
+use crypto::digest::Digest;
+use disc::{self, PS3Disc};
+use sector::Region;
+use iso9660::DirectoryRecord;
+use ird::IRDFile;
+
+/// Result of checking a single region against an IRD file
+#[derive(Debug, Clone, Copy)]
+pub struct RegionCheck {
+    /// The region that was checked
+    pub region: Region,
+    /// MD5 hash according to the IRD file
+    pub expected: [u8; 16],
+    /// MD5 hash of the region on the disc
+    pub actual: [u8; 16]
+}
+
+impl RegionCheck {
+    /// Whether the region matched the IRD file's hash
+    pub fn passed(&self) -> bool {
+        self.expected == self.actual
+    }
+}
+
+/// Calculate the MD5 of a disc region, decrypting sectors as needed
+///
+/// IRD hashes are of the original disc, so any 3k3y data in sector 1 is stripped out first.
+///
+/// `progress` is called with each sector number as it's hashed.
+pub fn region_md5<F: Read+Seek, P: FnMut(u32)>(disc: &mut PS3Disc<F>, region: &Region, mut progress: P) -> Result<[u8; 16]> {
+    let mut md5 = Md5::new();
+    for sector in region.start..(region.end+1) {
+        let mut buf = disc.read_sector(sector).chain_err(|| format!("Failed to read sector {}", sector))?;
+        if sector == 1 && disc.tagline_3k3y.is_some() {
+            disc::strip_3k3y(&mut buf);
+        }
+        md5.input(buf.as_ref());
+        progress(sector);
+    }
+    let mut hash = [0u8; 16];
+    md5.result(&mut hash);
+    Ok(hash)
+}
+
+/// Check a single region of a disc against the IRD file's hash for it
+pub fn check_region<F: Read+Seek, P: FnMut(u32)>(disc: &mut PS3Disc<F>, region: &Region, expected: [u8; 16], progress: P) -> Result<RegionCheck> {
+    let actual = region_md5(disc, region, progress)?;
+    Ok(RegionCheck {
+        region: *region,
+        expected, actual
+    })
+}
+
+/// How checking a single file went
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileStatus {
+    /// The file matches the IRD file's hash
+    Ok,
+    /// The file doesn't match, this is the MD5 hash of it on the disc
+    Mismatch([u8; 16]),
+    /// The file couldn't be read off the disc, and this is why
+    ReadError(String),
+    /// The file isn't in the disc's filesystem, so there's no knowing how long it is to hash it
+    ///
+    /// IRD files only say where each file starts, and their hashes are of exactly the file's bytes.
+    Unverifiable
+}
+
+/// Result of checking a single file against an IRD file
+#[derive(Debug, Clone)]
+pub struct FileCheck {
+    /// First sector of the file, which is how the IRD file identifies it
+    pub sector: u32,
+    /// Path of the file on the disc, if it was found in the disc's filesystem
+    pub path: Option<String>,
+    /// MD5 hash according to the IRD file
+    pub expected: [u8; 16],
+    pub status: FileStatus
+}
+
+impl FileCheck {
+    /// Whether the file matched the IRD file's hash
+    pub fn passed(&self) -> bool {
+        self.status == FileStatus::Ok
+    }
+
+    /// Whether the file was checked and is bad, as opposed to not being checkable at all
+    pub fn failed(&self) -> bool {
+        match self.status {
+            FileStatus::Mismatch(_) | FileStatus::ReadError(_) => true,
+            FileStatus::Ok | FileStatus::Unverifiable => false
+        }
+    }
+}
+
+/// Calculate the MD5 of a file on the disc, decrypting sectors as needed
+pub fn file_md5<F: Read+Seek>(disc: &mut PS3Disc<F>, file: &DirectoryRecord) -> Result<[u8; 16]> {
+    let mut md5 = Md5::new();
+    for extent in &file.extents {
+        let mut remaining = extent.length;
+        let mut sector = extent.start;
+        while remaining > 0 {
+            let buf = disc.read_sector(sector).chain_err(|| format!("Failed to read sector {}", sector))?;
+            let len = if remaining < 2048 { remaining as usize } else { 2048 };
+            md5.input(&buf[..len]);
+            remaining -= len as u64;
+            sector += 1;
+        }
+    }
+    let mut hash = [0u8; 16];
+    md5.result(&mut hash);
+    Ok(hash)
+}
+
+/// Check every file in an IRD file's hash list against the disc
+///
+/// Files are found by their starting sector in `files`, which should be everything in the disc's filesystem
+/// (see `iso9660::walk`), and hashed exactly. Any that aren't in `files` are `FileStatus::Unverifiable`,
+/// and any that can't be read are `FileStatus::ReadError`, the rest of the files are still checked.
+///
+/// It's an error if the IRD file lists a file that starts outside the disc, since it's not for this disc.
+///
+/// `progress` is called with each file as it's checked.
+pub fn check_files<F: Read+Seek, P: FnMut(&FileCheck)>(disc: &mut PS3Disc<F>, ird_file: &IRDFile, files: &[(String, DirectoryRecord)], mut progress: P) -> Result<Vec<FileCheck>> {
+    let files: HashMap<u32, &(String, DirectoryRecord)> = files.iter().map(|f| (f.1.start_sector(), f)).collect();
+    for &(sector, _) in &ird_file.file_hashes {
+        if sector < 0 || sector >= disc.total_sectors as i64 {
+            bail!("IRD file lists a file at sector {}, but the disc only has {} sectors", sector, disc.total_sectors);
+        }
+    }
+
+    let mut checks = vec![];
+    for &(sector, expected) in &ird_file.file_hashes {
+        let sector = sector as u32;
+        let check = match files.get(&sector) {
+            Some(&&(ref path, ref record)) => FileCheck {
+                sector, expected,
+                path: Some(path.clone()),
+                status: match file_md5(disc, record) {
+                    Ok(actual) if actual == expected => FileStatus::Ok,
+                    Ok(actual) => FileStatus::Mismatch(actual),
+                    Err(e) => FileStatus::ReadError(e.to_string())
+                }
+            },
+            None => FileCheck {
+                sector, expected,
+                path: None,
+                status: FileStatus::Unverifiable
+            }
+        };
+        progress(&check);
+        checks.push(check);
+    }
+    Ok(checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disc::tests::{test_disc, test_image};
+    use iso9660::Extent;
+
+    /// An IRD file with nothing but file hashes in it
+    fn ird(file_hashes: Vec<(i64, [u8; 16])>) -> IRDFile {
+        IRDFile {
+            version: 9,
+            game_id: "BLUS99999".to_string(),
+            game_name: "Test".to_string(),
+            update_ver: "0000".to_string(),
+            game_ver: "01.00".to_string(),
+            app_ver: "01.00".to_string(),
+            header_comp: vec![],
+            footer_comp: vec![],
+            region_hashes: vec![],
+            file_hashes,
+            pic_data: vec![0; 0x73],
+            data1: [0; 16],
+            data2: [0; 16],
+            unique_identifier: Some(0),
+            crc32: 0
+        }
+    }
+
+    fn file(name: &str, start: u32, length: u64) -> (String, DirectoryRecord) {
+        (name.to_string(), DirectoryRecord {
+            name: name.to_string(),
+            extents: vec![Extent { start, length }],
+            size: length,
+            flags: 0,
+            recorded: [0; 7],
+            position: 0
+        })
+    }
+
+    /// MD5 of `length` decrypted bytes starting at `sector`
+    fn md5_of(start: u32, length: usize) -> [u8; 16] {
+        let mut data = vec![0u8; 40 * 2048];
+        test_disc(test_image()).read_sectors_into(0, &mut data).unwrap();
+        let mut md5 = Md5::new();
+        md5.input(&data[(start as usize * 2048)..(start as usize * 2048 + length)]);
+        let mut hash = [0u8; 16];
+        md5.result(&mut hash);
+        hash
+    }
+
+    #[test]
+    fn files_are_hashed_exactly() {
+        let files = vec![file("EBOOT.BIN", 10, 3000), file("ICON0.PNG", 32, 2048)];
+        let ird_file = ird(vec![(10, md5_of(10, 3000)), (32, [0xAA; 16])]);
+        let checks = check_files(&mut test_disc(test_image()), &ird_file, &files, |_| {}).unwrap();
+        assert_eq!(checks.len(), 2);
+        assert_eq!(checks[0].status, FileStatus::Ok);
+        assert_eq!(checks[0].path, Some("EBOOT.BIN".to_string()));
+        assert_eq!(checks[1].status, FileStatus::Mismatch(md5_of(32, 2048)));
+        assert!(checks[1].failed());
+    }
+
+    #[test]
+    fn files_missing_from_the_filesystem_are_unverifiable() {
+        let ird_file = ird(vec![(10, md5_of(10, 3000)), (32, md5_of(32, 2048))]);
+        let checks = check_files(&mut test_disc(test_image()), &ird_file, &[], |_| {}).unwrap();
+        for check in &checks {
+            assert_eq!(check.status, FileStatus::Unverifiable);
+            assert!(!check.passed() && !check.failed());
+        }
+    }
+
+    #[test]
+    fn read_errors_only_fail_that_file() {
+        let mut image = test_image();
+        image.truncate(35 * 2048);
+        let files = vec![file("GONE.BIN", 36, 100), file("EBOOT.BIN", 10, 3000)];
+        let ird_file = ird(vec![(36, [0; 16]), (10, md5_of(10, 3000))]);
+        let mut seen = 0;
+        let checks = check_files(&mut test_disc(image), &ird_file, &files, |_| seen += 1).unwrap();
+        assert_eq!(seen, 2);
+        match checks[0].status {
+            FileStatus::ReadError(_) => {},
+            ref status => panic!("expected a read error, got {:?}", status)
+        }
+        assert!(checks[0].failed());
+        assert_eq!(checks[1].status, FileStatus::Ok);
+    }
+
+    #[test]
+    fn sectors_off_the_disc_are_rejected() {
+        for &sector in &[-1, 40, 1 << 32] {
+            let ird_file = ird(vec![(10, [0; 16]), (sector, [0; 16])]);
+            assert!(check_files(&mut test_disc(test_image()), &ird_file, &[], |_| {}).is_err(), "sector {}", sector);
+        }
+    }
+}